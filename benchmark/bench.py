@@ -138,8 +138,11 @@ def setup_schema(conn, row_count):
 
 # --- Benchmark runners ---
 
-def bench_pigiaminja_copy(conn, row_count, warmup, iterations):
-    """Benchmark A: COPY TO with pigiaminja jinja format."""
+def bench_pigiaminja_copy(conn, row_count, warmup, iterations, message_size=None):
+    """Benchmark A: COPY TO with pigiaminja jinja format. With `message_size`
+    (in kB), pigiaminja.copy_message_size is set to it for the run."""
+    if message_size is not None:
+        conn.execute(f"SET pigiaminja.copy_message_size = {message_size}")
     copy_sql = (
         f"COPY (SELECT {COLUMNS} FROM bench_data) "
         f"TO STDOUT (FORMAT 'jinja', TEMPLATE '{JINJA_TEMPLATE}')"
@@ -163,7 +166,12 @@ def bench_pigiaminja_copy(conn, row_count, warmup, iterations):
             byte_count = sink.byte_count
             line_count = sink.line_count
 
-    return BenchResult("pigiaminja COPY jinja", row_count, times, byte_count, line_count)
+    if message_size is None:
+        approach = "pigiaminja COPY jinja"
+    else:
+        conn.execute("RESET pigiaminja.copy_message_size")
+        approach = f"pigiaminja, {message_size}kB messages"
+    return BenchResult(approach, row_count, times, byte_count, line_count)
 
 
 def bench_native_copy_csv(conn, row_count, warmup, iterations):
@@ -261,6 +269,9 @@ def print_results(all_results):
             print(f"    {ratio_str('pigiaminja', jinja_r.mean, 'psycopg', client_r.mean)}")
         if client_r.mean > 0 and csv_r.mean > 0:
             print(f"    {ratio_str('native CSV', csv_r.mean, 'psycopg', client_r.mean)}")
+        for r in results[3:]:
+            if r.mean > 0:
+                print(f"    {ratio_str('pigiaminja', jinja_r.mean, r.approach, r.mean)}")
 
         print(f"\n  Data sizes:")
         for r in results:
@@ -338,14 +349,20 @@ def main():
         r3 = bench_psycopg_client(conn, row_count, args.warmup, args.iterations)
         print(f"    mean={r3.mean:.3f}s  ({r3.throughput:,.0f} rows/s)")
 
+        # The same export with one CopyData message per row, to see what
+        # batching rows into larger messages is worth
+        print(f"  Running pigiaminja COPY jinja, one message per row...", flush=True)
+        r4 = bench_pigiaminja_copy(conn, row_count, args.warmup, args.iterations, message_size=0)
+        print(f"    mean={r4.mean:.3f}s  ({r4.throughput:,.0f} rows/s)")
+
         # Validate: native CSV and psycopg should produce row_count lines.
-        # pigiaminja COPY sends each row as a separate protocol message without
-        # trailing newlines, so line_count will be 0 - that's expected.
+        # pigiaminja's template renders rows without trailing newlines, so its
+        # line_count will be 0 - that's expected.
         for r in [r2, r3]:
             if r.line_count != row_count:
                 print(f"  WARNING: {r.approach} produced {r.line_count} lines, expected {row_count}")
 
-        all_results[row_count] = [r1, r2, r3]
+        all_results[row_count] = [r1, r2, r3, r4]
 
     print_results(all_results)
 
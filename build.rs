@@ -0,0 +1,35 @@
+//! Compiles `src/copy_hook/pq_shim.c`, the few backend libpq entry points
+//! pgrx has no bindings for, against the server headers of the PostgreSQL
+//! being built for, like pgrx-pg-sys does for its own shim.
+
+use pgrx_pg_config::{PgConfig, Pgrx};
+
+const SHIM: &str = "src/copy_hook/pq_shim.c";
+
+fn main() {
+    println!("cargo:rerun-if-changed={SHIM}");
+    println!("cargo:rerun-if-env-changed=PGRX_PG_CONFIG_PATH");
+
+    // docs.rs has no PostgreSQL to build against, and pgrx skips it too
+    if std::env::var("DOCS_RS").as_deref() == Ok("1") {
+        return;
+    }
+
+    let pg_config = PgConfig::from_env().unwrap_or_else(|_| {
+        let major = ["14", "15", "16", "17", "18"]
+            .into_iter()
+            .find(|major| std::env::var_os(format!("CARGO_FEATURE_PG{major}")).is_some())
+            .expect("one of the pg14 to pg18 features must be enabled");
+        Pgrx::from_config()
+            .and_then(|pgrx| pgrx.get(&format!("pg{major}")))
+            .unwrap_or_else(|e| panic!("no pg_config for pg{major}: {e}"))
+    });
+    let include_dir = pg_config
+        .includedir_server()
+        .unwrap_or_else(|e| panic!("could not find the server headers: {e}"));
+
+    cc::Build::new()
+        .include(include_dir)
+        .file(SHIM)
+        .compile("pigiaminja_pq_shim");
+}
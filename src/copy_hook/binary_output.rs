@@ -0,0 +1,123 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::sync::Arc;
+
+use minijinja::value::ValueKind;
+use minijinja::{escape_formatter, Environment, Error, Output, State, Value};
+use pgrx::pg_sys;
+
+/// Closes a placeholder. U+FFFF is a noncharacter, which text has no
+/// business containing, and the key before it makes a forged one hopeless.
+const PLACEHOLDER_END: char = '\u{FFFF}';
+
+thread_local! {
+    /// The `bytea` values printed so far by the row rendering on this thread
+    static PRINTED: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Carries `bytea` values past the renderer, which only produces text, for
+/// BINARY_OUTPUT. The formatter sets a printed value aside and prints a
+/// placeholder naming it instead, and the writers put the raw bytes back in
+/// its place. Placeholders start with a random key drawn for each COPY, so
+/// no text or template can make one up, and they come through macros and
+/// `{% set %}` blocks, which print their output a second time, intact.
+#[derive(Clone)]
+pub(super) struct BinaryOutput {
+    prefix: Arc<str>,
+}
+
+impl BinaryOutput {
+    pub fn new() -> Self {
+        let mut key = [0u8; 16];
+        if !unsafe { pg_sys::pg_strong_random(key.as_mut_ptr() as _, key.len()) } {
+            pgrx::error!("could not generate a random key for BINARY_OUTPUT");
+        }
+
+        let mut prefix = String::from(PLACEHOLDER_END);
+        for byte in key {
+            let _ = write!(prefix, "{:02x}", byte);
+        }
+        prefix.push(':');
+
+        // Left behind by a COPY that failed mid-row
+        PRINTED.with_borrow_mut(Vec::clear);
+
+        BinaryOutput {
+            prefix: prefix.into(),
+        }
+    }
+
+    /// Print `bytea` values through placeholders; anything else as the
+    /// default formatter would.
+    pub fn add_to(&self, env: &mut Environment) {
+        let this = self.clone();
+        env.set_formatter(move |out, state, value| this.format(out, state, value));
+    }
+
+    fn format(&self, out: &mut Output, state: &State, value: &Value) -> Result<(), Error> {
+        if value.kind() != ValueKind::Bytes {
+            return escape_formatter(out, state, value);
+        }
+
+        let bytes = value.as_bytes().unwrap_or_default().to_vec();
+        let idx = PRINTED.with_borrow_mut(|printed| {
+            printed.push(bytes);
+            printed.len() - 1
+        });
+        // In one write, so that `restore` sees it whole
+        out.write_str(&format!("{}{}{}", self.prefix, idx, PLACEHOLDER_END))?;
+        Ok(())
+    }
+
+    /// Put the bytes back in place of the placeholders in a piece of the
+    /// rendered output. A write never splits a placeholder: each is printed
+    /// whole, and printed again whole as part of a macro's output.
+    pub fn restore<'a>(&self, rendered: &'a [u8]) -> Cow<'a, [u8]> {
+        PRINTED.with_borrow(|printed| {
+            if printed.is_empty() {
+                return Cow::Borrowed(rendered);
+            }
+
+            let prefix = self.prefix.as_bytes();
+            let mut end = [0; 4];
+            let end = PLACEHOLDER_END.encode_utf8(&mut end).as_bytes();
+
+            let mut out = Vec::new();
+            let mut rest = rendered;
+            let mut found = false;
+            while let Some(pos) = rest.windows(prefix.len()).position(|w| w == prefix) {
+                found = true;
+                let after = &rest[pos + prefix.len()..];
+                let digits = after.iter().take_while(|b| b.is_ascii_digit()).count();
+                let bytes = std::str::from_utf8(&after[..digits])
+                    .ok()
+                    .and_then(|idx| idx.parse::<usize>().ok())
+                    .and_then(|idx| printed.get(idx));
+
+                out.extend_from_slice(&rest[..pos]);
+                match (bytes, after[digits..].strip_prefix(end)) {
+                    (Some(bytes), Some(tail)) => {
+                        out.extend_from_slice(bytes);
+                        rest = tail;
+                    }
+                    _ => {
+                        out.extend_from_slice(prefix);
+                        rest = after;
+                    }
+                }
+            }
+
+            if !found {
+                return Cow::Borrowed(rendered);
+            }
+            out.extend_from_slice(rest);
+            Cow::Owned(out)
+        })
+    }
+
+    /// Forget this thread's printed values once its row is out.
+    pub fn finish_row() {
+        PRINTED.with_borrow_mut(Vec::clear);
+    }
+}
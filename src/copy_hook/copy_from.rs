@@ -0,0 +1,409 @@
+use std::ffi::{c_int, c_void, CStr};
+use std::io::BufReader;
+
+use pgrx::pg_sys::errcodes::PgSqlErrorCode;
+use pgrx::{
+    ereport, is_a, pg_guard,
+    pg_sys::{
+        addNSItemToQuery, addRangeTableEntryForRelation, assign_expr_collations, canonicalize_qual,
+        check_enable_rls, coerce_to_boolean, defGetBoolean, defGetString, eval_const_expressions,
+        free_parsestate, makeString, make_ands_implicit, make_parsestate, pstrdup, table_close,
+        table_openrv, transformExpr, BeginCopyFrom, CheckEnableRlsResult, CommandTag, CopyFrom,
+        CopyGetAttnums, CopyStmt, DefElem, EndCopyFrom, Expr, InvalidOid, NoLock, Node,
+        NodeTag::T_CopyStmt, ParseExprKind, PlannedStmt, PreventCommandIfReadOnly, QueryCompletion,
+        RowExclusiveLock, XactReadOnly,
+    },
+    PgBox, PgList,
+};
+
+use super::copy_to::{copy_stmt_get_option, is_jinja_format_option};
+use super::hook::ENABLE_JINJA_COPY_HOOK;
+use super::input::CopySource;
+use super::pattern::ImportPattern;
+use super::pg_compat::{check_copy_from_permissions, str_val};
+use super::record::{RecordDelimiter, RecordPosition, RecordReader};
+
+/// COPY options forwarded to the underlying text-format COPY FROM
+const PASSTHROUGH_OPTIONS: [&str; 2] = ["encoding", "freeze"];
+
+/// Import state for the COPY FROM currently running. BeginCopyFrom's data
+/// source callback takes no argument, so it finds its input through here.
+static mut CURRENT_IMPORT: *mut JinjaImport = std::ptr::null_mut();
+
+/// Reads input records, matches them against the import pattern and hands
+/// them to COPY FROM as text-format rows, so the captured strings go through
+/// each column's input function, defaults, constraints, triggers and the
+/// WHERE clause exactly like a native COPY.
+struct JinjaImport {
+    reader: RecordReader<BufReader<CopySource>>,
+    pattern: ImportPattern,
+    // Capture group for each column of the COPY column list
+    field_groups: Vec<usize>,
+    // LOG_ERRORS: skip records that do not match instead of failing
+    log_errors: bool,
+    skipped: u64,
+    record: Vec<u8>,
+    pending: Vec<u8>,
+    pending_pos: usize,
+}
+
+impl JinjaImport {
+    fn fill(&mut self, out: &mut [u8]) -> usize {
+        while self.pending_pos >= self.pending.len() {
+            if !self.next_record() {
+                return 0;
+            }
+        }
+
+        let count = (self.pending.len() - self.pending_pos).min(out.len());
+        out[..count].copy_from_slice(&self.pending[self.pending_pos..self.pending_pos + count]);
+        self.pending_pos += count;
+        count
+    }
+
+    /// Convert the next matching input record into a COPY text row in
+    /// `pending`. Returns false at end of input.
+    fn next_record(&mut self) -> bool {
+        self.pending.clear();
+        self.pending_pos = 0;
+
+        loop {
+            let position = self
+                .reader
+                .next_record(&mut self.record)
+                .unwrap_or_else(|e| pgrx::error!("Failed to read COPY input: {}", e));
+            let Some(position) = position else {
+                return false;
+            };
+
+            let Some(captures) = self.pattern.captures(&self.record) else {
+                self.report_mismatch(position);
+                continue;
+            };
+
+            for (i, group) in self.field_groups.iter().enumerate() {
+                if i > 0 {
+                    self.pending.push(b'\t');
+                }
+                match captures.get(*group) {
+                    Some(value) => escape_copy_text(value.as_bytes(), &mut self.pending),
+                    // A group that did not participate in the match is NULL
+                    None => self.pending.extend_from_slice(b"\\N"),
+                }
+            }
+            self.pending.push(b'\n');
+
+            return true;
+        }
+    }
+
+    /// Fail the COPY on a record that does not match, or with LOG_ERRORS,
+    /// log it and move on
+    fn report_mismatch(&mut self, position: RecordPosition) {
+        let message = if position.line_count == 1 {
+            format!(
+                "line {} does not match the import pattern",
+                position.first_line
+            )
+        } else {
+            format!(
+                "record at lines {}-{} does not match the import pattern",
+                position.first_line,
+                position.first_line + position.line_count - 1
+            )
+        };
+        let detail = format!("Record: \"{}\"", String::from_utf8_lossy(&self.record));
+
+        if !self.log_errors {
+            ereport!(
+                ERROR,
+                PgSqlErrorCode::ERRCODE_BAD_COPY_FILE_FORMAT,
+                message,
+                detail
+            );
+        }
+
+        ereport!(
+            WARNING,
+            PgSqlErrorCode::ERRCODE_BAD_COPY_FILE_FORMAT,
+            format!("{}, skipping it", message),
+            detail
+        );
+        self.skipped += 1;
+    }
+}
+
+/// Escape a value for COPY's text format with the default delimiter
+fn escape_copy_text(value: &[u8], out: &mut Vec<u8>) {
+    for &byte in value {
+        match byte {
+            b'\\' => out.extend_from_slice(b"\\\\"),
+            b'\t' => out.extend_from_slice(b"\\t"),
+            b'\n' => out.extend_from_slice(b"\\n"),
+            b'\r' => out.extend_from_slice(b"\\r"),
+            _ => out.push(byte),
+        }
+    }
+}
+
+/// Makes an import the current one for the data source callback, restoring
+/// the previous one (a COPY FROM run by a trigger) when dropped, including
+/// when an ERROR unwinds through CopyFrom.
+struct CurrentImportGuard {
+    previous: *mut JinjaImport,
+}
+
+impl CurrentImportGuard {
+    unsafe fn install(import: &mut JinjaImport) -> Self {
+        let previous = CURRENT_IMPORT;
+        CURRENT_IMPORT = import;
+        CurrentImportGuard { previous }
+    }
+}
+
+impl Drop for CurrentImportGuard {
+    fn drop(&mut self) {
+        unsafe { CURRENT_IMPORT = self.previous };
+    }
+}
+
+#[pg_guard]
+extern "C-unwind" fn jinja_import_data_source(
+    outbuf: *mut c_void,
+    _minread: c_int,
+    maxread: c_int,
+) -> c_int {
+    unsafe {
+        let import = CURRENT_IMPORT
+            .as_mut()
+            .expect("jinja COPY FROM data requested without an active import");
+        let out = std::slice::from_raw_parts_mut(outbuf as *mut u8, maxread as usize);
+        import.fill(out) as c_int
+    }
+}
+
+/// Execute COPY FROM with FORMAT jinja: parse each input line with the
+/// PATTERN or TEMPLATE option and load the captures into the table
+pub(crate) fn execute_copy_from_jinja(
+    p_stmt: &PgBox<PlannedStmt>,
+    query_string: &CStr,
+    query_completion: *mut QueryCompletion,
+) {
+    unsafe {
+        let copy_stmt = PgBox::<CopyStmt>::from_pg(p_stmt.utilityStmt as _);
+
+        let pattern = extract_import_pattern(p_stmt).unwrap_or_else(|e| pgrx::error!("{}", e));
+        let record_delimiter = extract_record_delimiter(p_stmt);
+        let log_errors = extract_log_errors(p_stmt);
+
+        let pstate = make_parsestate(std::ptr::null_mut());
+        (*pstate).p_sourcetext = query_string.as_ptr();
+
+        let rel = table_openrv(copy_stmt.relation, RowExclusiveLock as _);
+
+        if XactReadOnly && !(*rel).rd_islocaltemp {
+            PreventCommandIfReadOnly(c"COPY FROM".as_ptr());
+        }
+
+        let nsitem = addRangeTableEntryForRelation(
+            pstate,
+            rel,
+            RowExclusiveLock as _,
+            std::ptr::null_mut(),
+            false,
+            false,
+        );
+
+        let where_clause = if copy_stmt.whereClause.is_null() {
+            std::ptr::null_mut()
+        } else {
+            addNSItemToQuery(pstate, nsitem, false, true, true);
+
+            let mut where_clause = transformExpr(
+                pstate,
+                copy_stmt.whereClause,
+                ParseExprKind::EXPR_KIND_COPY_WHERE,
+            );
+            where_clause = coerce_to_boolean(pstate, where_clause, c"WHERE".as_ptr());
+            assign_expr_collations(pstate, where_clause);
+            where_clause = eval_const_expressions(std::ptr::null_mut(), where_clause);
+            where_clause = canonicalize_qual(where_clause as *mut Expr, false) as *mut Node;
+            make_ands_implicit(where_clause as *mut Expr) as *mut Node
+        };
+
+        // Without a column list, the pattern's groups name the columns
+        let attnamelist = if copy_stmt.attlist.is_null() {
+            let mut names = PgList::<Node>::new();
+            for column in pattern.column_names() {
+                let column = std::ffi::CString::new(column).expect("column name contains NUL");
+                names.push(makeString(pstrdup(column.as_ptr())) as *mut Node);
+            }
+            names.into_pg()
+        } else {
+            copy_stmt.attlist
+        };
+
+        let attnums = CopyGetAttnums((*rel).rd_att, rel, attnamelist);
+        check_copy_from_permissions(pstate, nsitem, attnums);
+
+        if check_enable_rls((*rel).rd_id, InvalidOid, false)
+            == CheckEnableRlsResult::RLS_ENABLED as c_int
+        {
+            ereport!(
+                ERROR,
+                PgSqlErrorCode::ERRCODE_FEATURE_NOT_SUPPORTED,
+                "COPY FROM not supported with row-level security",
+                "Use INSERT statements instead."
+            );
+        }
+
+        let field_groups = PgList::<Node>::from_pg(attnamelist)
+            .iter_ptr()
+            .map(|name| {
+                let name = CStr::from_ptr(str_val(name)).to_string_lossy();
+                pattern.group_index(&name).unwrap_or_else(|| {
+                    pgrx::error!("column \"{}\" is not captured by the import pattern", name)
+                })
+            })
+            .collect();
+
+        let source = CopySource::from_copy_stmt(copy_stmt.filename, copy_stmt.is_program)
+            .unwrap_or_else(|e| pgrx::error!("{}", e));
+
+        let mut import = JinjaImport {
+            reader: RecordReader::new(BufReader::new(source), record_delimiter),
+            pattern,
+            field_groups,
+            log_errors,
+            skipped: 0,
+            record: Vec::new(),
+            pending: Vec::new(),
+            pending_pos: 0,
+        };
+
+        let processed = {
+            let _current = CurrentImportGuard::install(&mut import);
+
+            let cstate = BeginCopyFrom(
+                pstate,
+                rel,
+                where_clause,
+                std::ptr::null(),
+                false,
+                Some(jinja_import_data_source),
+                attnamelist,
+                passthrough_options(&copy_stmt),
+            );
+            let processed = CopyFrom(cstate);
+            EndCopyFrom(cstate);
+            processed
+        };
+
+        import
+            .reader
+            .get_mut()
+            .get_mut()
+            .finalize()
+            .unwrap_or_else(|e| pgrx::error!("{}", e));
+
+        match import.skipped {
+            0 => {}
+            1 => pgrx::notice!("1 record was skipped because it did not match the import pattern"),
+            skipped => pgrx::notice!(
+                "{} records were skipped because they did not match the import pattern",
+                skipped
+            ),
+        }
+
+        table_close(rel, NoLock as _);
+        free_parsestate(pstate);
+
+        if !query_completion.is_null() {
+            let mut completion_tag = PgBox::from_pg(query_completion);
+            completion_tag.nprocessed = processed;
+            completion_tag.commandTag = CommandTag::CMDTAG_COPY;
+        }
+    }
+}
+
+/// Check if a COPY statement is a COPY FROM with Jinja format
+pub(crate) fn is_copy_from_jinja_stmt(p_stmt: &PgBox<PlannedStmt>) -> bool {
+    // The GUC pigiaminja.enable_jinja_copy_hook must be set to true
+    if !ENABLE_JINJA_COPY_HOOK.get() {
+        return false;
+    }
+
+    let is_copy_stmt = unsafe { is_a(p_stmt.utilityStmt, T_CopyStmt) };
+
+    if !is_copy_stmt {
+        return false;
+    }
+
+    let copy_stmt = unsafe { PgBox::<CopyStmt>::from_pg(p_stmt.utilityStmt as _) };
+
+    if !copy_stmt.is_from {
+        return false;
+    }
+
+    is_jinja_format_option(p_stmt)
+}
+
+/// Build the import pattern from the PATTERN or TEMPLATE option
+fn extract_import_pattern(p_stmt: &PgBox<PlannedStmt>) -> Result<ImportPattern, String> {
+    let pattern_option = copy_stmt_get_option(p_stmt, "pattern");
+    let template_option = copy_stmt_get_option(p_stmt, "template");
+
+    match (pattern_option.is_null(), template_option.is_null()) {
+        (false, false) => Err("PATTERN and TEMPLATE cannot be used together".to_string()),
+        (true, true) => {
+            Err("pattern or template option is required for jinja COPY FROM".to_string())
+        }
+        (false, true) => ImportPattern::from_regex(&option_string(&pattern_option)),
+        (true, false) => ImportPattern::from_template(&option_string(&template_option)),
+    }
+}
+
+/// Split records at the RECORD_DELIMITER option, one line per record by default
+fn extract_record_delimiter(p_stmt: &PgBox<PlannedStmt>) -> RecordDelimiter {
+    let option = copy_stmt_get_option(p_stmt, "record_delimiter");
+
+    if option.is_null() {
+        return RecordDelimiter::default();
+    }
+
+    RecordDelimiter::from_option(&option_string(&option))
+}
+
+fn extract_log_errors(p_stmt: &PgBox<PlannedStmt>) -> bool {
+    let option = copy_stmt_get_option(p_stmt, "log_errors");
+
+    !option.is_null() && unsafe { defGetBoolean(option.as_ptr()) }
+}
+
+fn option_string(option: &PgBox<DefElem>) -> String {
+    let value = unsafe { defGetString(option.as_ptr()) };
+
+    unsafe { CStr::from_ptr(value) }
+        .to_str()
+        .unwrap_or_else(|e| panic!("copy option is not a valid CString: {e}"))
+        .to_string()
+}
+
+/// The subset of the statement's options that native COPY FROM understands
+/// and that still apply to the rows we generate
+fn passthrough_options(copy_stmt: &PgBox<CopyStmt>) -> *mut pgrx::pg_sys::List {
+    let copy_options = unsafe { PgList::<DefElem>::from_pg(copy_stmt.options) };
+
+    let mut options = PgList::<DefElem>::new();
+    for option in copy_options.iter_ptr() {
+        let name = unsafe { CStr::from_ptr((*option).defname) };
+        if PASSTHROUGH_OPTIONS
+            .iter()
+            .any(|passthrough| name.to_bytes() == passthrough.as_bytes())
+        {
+            options.push(option);
+        }
+    }
+
+    options.into_pg()
+}
@@ -1,23 +1,34 @@
+use std::collections::BTreeMap;
 use std::ffi::{CStr, CString};
 
+use minijinja::Value;
+
+use pgrx::pg_sys::errcodes::PgSqlErrorCode;
 use pgrx::{
     is_a,
     pg_sys::{
-        defGetString, makeStringInfo, pg_plan_query, pq_beginmessage, pq_endmessage,
-        pq_putemptymessage, pq_sendbyte, pq_sendint16, A_Star, ColumnRef, CommandTag, CopyStmt,
+        defGetBoolean, defGetInt64, defGetString, ereport, makeStringInfo, parse_int,
+        pg_plan_query, pgstat_progress_end_command, pgstat_progress_start_command,
+        pgstat_progress_update_param, pq_beginmessage, pq_endmessage, pq_putemptymessage,
+        pq_sendbyte, pq_sendint16, superuser, A_Star, ColumnRef, CommandTag, CopyStmt,
         CreateNewPortal, DefElem, DestReceiver, GetActiveSnapshot, Node,
         NodeTag::{self, T_CopyStmt},
-        ParamListInfoData, PlannedStmt, PortalDefineQuery, PortalDrop, PortalRun, PortalStart,
-        QueryCompletion, QueryEnvironment, RangeVar, RawStmt, ResTarget, SelectStmt,
-        CURSOR_OPT_PARALLEL_OK,
+        Oid, ParamListInfoData, PlannedStmt, PortalDefineQuery, PortalDrop, PortalRun, PortalStart,
+        ProgressCommandType, QueryCompletion, QueryEnvironment, RangeTblEntry, RangeVar, RawStmt,
+        ResTarget, SelectStmt, CURSOR_OPT_PARALLEL_OK, GUC_UNIT_KB, PROGRESS_COPY_COMMAND,
+        PROGRESS_COPY_COMMAND_TO, PROGRESS_COPY_TYPE,
     },
     AllocatedByRust, PgBox, PgList,
 };
 
-use super::dest_receiver::create_jinja_dest_receiver;
+use super::dest_receiver::{
+    create_jinja_dest_receiver, JinjaDestReceiver, OutputLimits, MAX_OUTPUT_BYTES,
+    MAX_ROW_OUTPUT_BYTES,
+};
 use super::hook::ENABLE_JINJA_COPY_HOOK;
 use super::output::CopyDestination;
 use super::pg_compat::pg_analyze_and_rewrite;
+use super::preset::{extends_template, Preset};
 
 /// Execute COPY TO with Jinja template formatting using DestReceiver pattern
 pub(crate) fn execute_copy_to_jinja(
@@ -30,21 +41,44 @@ pub(crate) fn execute_copy_to_jinja(
     unsafe {
         let copy_stmt = PgBox::<CopyStmt>::from_pg(p_stmt.utilityStmt as _);
 
-        // Extract the template content from the COPY statement
-        let template_content = extract_jinja_template(p_stmt)
-            .unwrap_or_else(|| pgrx::error!("template option is required for jinja format"));
+        let preset = extract_preset(p_stmt);
+
+        // Extract the template content from the COPY statement. A preset on
+        // its own renders the preset unchanged.
+        let template_content = match (extract_jinja_template(p_stmt), preset) {
+            (Some(template), Some(preset)) if !extends_template(&template) => pgrx::error!(
+                "a TEMPLATE given with PRESET must extend it: start it with {{% extends \"{}\" %}}",
+                preset.name()
+            ),
+            (Some(template), _) => template,
+            (None, Some(preset)) => format!("{{% extends \"{}\" %}}", preset.name()),
+            (None, None) => pgrx::error!("template option is required for jinja format"),
+        };
 
         let template_content_cstr =
             CString::new(template_content).expect("Failed to create CString from template content");
 
-        // Detect the output destination
-        let output_destination = CopyDestination::from_copy_stmt(
-            copy_stmt.filename,
-            copy_stmt.is_program,
-        )
-        .unwrap_or_else(|e| pgrx::error!("{}", e));
+        let output_limits = extract_output_limits(p_stmt);
+        let copy_options = extract_copy_options(p_stmt);
+        let params = extract_params(p_stmt);
+        let binary_output = extract_binary_output(p_stmt);
+        let dry_run = extract_dry_run(p_stmt);
+        let limit_rows = extract_limit_rows(p_stmt);
+
+        // Detect the output destination. A dry run writes nowhere, so it
+        // neither creates the file nor starts the program.
+        let output_destination = if dry_run {
+            CopyDestination::Discard
+        } else {
+            CopyDestination::from_copy_stmt(copy_stmt.filename, copy_stmt.is_program)
+                .unwrap_or_else(|e| pgrx::error!("{}", e))
+        };
 
-        let is_stdout = output_destination.is_stdout();
+        // Taken from the statement rather than the destination: a dry run
+        // TO STDOUT still answers with an (empty) COPY OUT, which clients like
+        // psycopg's `cursor.copy` expect.
+        let is_stdout = copy_stmt.filename.is_null() && !copy_stmt.is_program;
+        let output_destination_type = output_destination.progress_type();
 
         // Box the destination to pass as pointer
         let output_destination_ptr = Box::into_raw(Box::new(output_destination));
@@ -53,12 +87,25 @@ pub(crate) fn execute_copy_to_jinja(
         let jinja_dest = create_jinja_dest_receiver(
             template_content_cstr.as_ptr(),
             output_destination_ptr,
+            output_limits,
+            preset,
+            copy_options,
+            params,
+            binary_output,
         );
+        if dry_run || limit_rows > 0 {
+            (*jinja_dest).collect_timings();
+        }
 
         // Prepare parameters - create from null pointers
         let params = PgBox::<ParamListInfoData>::from_pg(std::ptr::null_mut());
         let query_env = PgBox::<QueryEnvironment>::from_pg(std::ptr::null_mut());
 
+        // Send COPY begin message (only for STDOUT)
+        if is_stdout {
+            send_copy_begin(1, binary_output); // 1 column
+        }
+
         // Execute with our custom DestReceiver
         let processed = execute_copy_to_with_dest_receiver(
             p_stmt,
@@ -66,9 +113,15 @@ pub(crate) fn execute_copy_to_jinja(
             &params,
             &query_env,
             &PgBox::from_pg(jinja_dest as *mut DestReceiver),
-            is_stdout,
+            limit_rows,
+            output_destination_type,
         );
 
+        // Send COPY end message (only for STDOUT)
+        if is_stdout {
+            send_copy_end();
+        }
+
         // Set completion status
         if !query_completion.is_null() {
             let mut completion_tag = PgBox::from_pg(query_completion);
@@ -101,10 +154,11 @@ pub(crate) fn is_copy_to_jinja_stmt(p_stmt: &PgBox<PlannedStmt>) -> bool {
     // Check if format is jinja
     let is_jinja = is_jinja_format_option(p_stmt);
 
-    // If format is jinja, template option is mandatory
+    // If format is jinja, template option is mandatory unless a preset is used
     if is_jinja {
         let template_option = copy_stmt_get_option(p_stmt, "template");
-        if template_option.is_null() {
+        let preset_option = copy_stmt_get_option(p_stmt, "preset");
+        if template_option.is_null() && preset_option.is_null() {
             pgrx::error!("template option is required when using jinja format");
         }
     }
@@ -131,8 +185,184 @@ pub(crate) fn extract_jinja_template(p_stmt: &PgBox<PlannedStmt>) -> Option<Stri
     Some(template_content.to_string())
 }
 
+/// The statement's options as name/value strings, for the `options` global.
+/// The template is left out: it is the one thing the template already knows.
+fn extract_copy_options(p_stmt: &PgBox<PlannedStmt>) -> Vec<(String, String)> {
+    let copy_stmt = unsafe { PgBox::<CopyStmt>::from_pg(p_stmt.utilityStmt as _) };
+    let copy_options = unsafe { PgList::<DefElem>::from_pg(copy_stmt.options) };
+
+    copy_options
+        .iter_ptr()
+        .filter_map(|option| unsafe {
+            let name = CStr::from_ptr((*option).defname)
+                .to_string_lossy()
+                .into_owned();
+            if name == "template" {
+                return None;
+            }
+
+            // A bare option such as FREEZE means true, as in defGetBoolean
+            let value = if (*option).arg.is_null() {
+                "true".to_string()
+            } else {
+                CStr::from_ptr(defGetString(option))
+                    .to_string_lossy()
+                    .into_owned()
+            };
+            Some((name, value))
+        })
+        .collect()
+}
+
+/// Parse the PARAMS option, a JSON object of values the query does not
+/// provide. Without it `params` is an empty map, so lookups are undefined
+/// rather than errors.
+fn extract_params(p_stmt: &PgBox<PlannedStmt>) -> Value {
+    let params_option = copy_stmt_get_option(p_stmt, "params");
+
+    if params_option.is_null() {
+        return Value::from(BTreeMap::<String, Value>::new());
+    }
+
+    let params = unsafe { CStr::from_ptr(defGetString(params_option.as_ptr())) }.to_string_lossy();
+
+    let params = serde_json::from_str::<serde_json::Value>(&params).unwrap_or_else(|e| {
+        ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+            format!("invalid value for PARAMS option: {}", e)
+        );
+    });
+
+    if !params.is_object() {
+        ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+            "PARAMS option must be a JSON object"
+        );
+    }
+
+    Value::from_serialize(params)
+}
+
+/// The BINARY_OUTPUT option: send a binary-format CopyOutResponse and pass
+/// `bytea` values through as raw bytes.
+fn extract_binary_output(p_stmt: &PgBox<PlannedStmt>) -> bool {
+    let option = copy_stmt_get_option(p_stmt, "binary_output");
+
+    !option.is_null() && unsafe { defGetBoolean(option.as_ptr()) }
+}
+
+/// The DRY_RUN option: render the rows but write them nowhere, reporting
+/// where the time went.
+fn extract_dry_run(p_stmt: &PgBox<PlannedStmt>) -> bool {
+    let option = copy_stmt_get_option(p_stmt, "dry_run");
+
+    !option.is_null() && unsafe { defGetBoolean(option.as_ptr()) }
+}
+
+/// The LIMIT_ROWS option: how many rows to render, 0 meaning all of them.
+fn extract_limit_rows(p_stmt: &PgBox<PlannedStmt>) -> i64 {
+    let option = copy_stmt_get_option(p_stmt, "limit_rows");
+
+    if option.is_null() {
+        return 0;
+    }
+
+    let limit_rows = unsafe { defGetInt64(option.as_ptr()) };
+    if limit_rows < 0 {
+        ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+            format!("LIMIT_ROWS must not be negative: {}", limit_rows)
+        );
+    }
+
+    limit_rows
+}
+
+/// Look up the built-in template named by the PRESET option
+fn extract_preset(p_stmt: &PgBox<PlannedStmt>) -> Option<Preset> {
+    let preset_option = copy_stmt_get_option(p_stmt, "preset");
+
+    if preset_option.is_null() {
+        return None;
+    }
+
+    let preset = unsafe { CStr::from_ptr(defGetString(preset_option.as_ptr())) }.to_string_lossy();
+
+    let preset = Preset::from_name(&preset).unwrap_or_else(|| {
+        ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+            format!("invalid value for PRESET option: \"{}\"", preset),
+            "Valid presets are ndjson, markdown, html_table and xml."
+        );
+    });
+
+    Some(preset)
+}
+
+/// Resolve the output size caps from the pigiaminja.max_output_bytes /
+/// pigiaminja.max_row_output_bytes GUCs, overridden by the MAX_OUTPUT_BYTES /
+/// MAX_ROW_OUTPUT_BYTES COPY options. Any role may tighten a cap for its own
+/// COPY; lifting or raising one takes a superuser, like the GUCs themselves.
+fn extract_output_limits(p_stmt: &PgBox<PlannedStmt>) -> OutputLimits {
+    OutputLimits {
+        max_output_bytes: resolve_output_limit(p_stmt, "max_output_bytes", MAX_OUTPUT_BYTES.get()),
+        max_row_output_bytes: resolve_output_limit(
+            p_stmt,
+            "max_row_output_bytes",
+            MAX_ROW_OUTPUT_BYTES.get(),
+        ),
+    }
+}
+
+fn resolve_output_limit(p_stmt: &PgBox<PlannedStmt>, option_name: &str, setting_kb: i32) -> u64 {
+    let setting = setting_kb as u64 * 1024;
+
+    let option = copy_stmt_get_option(p_stmt, option_name);
+    if option.is_null() {
+        return setting;
+    }
+
+    // Same syntax as the GUC: a size with a unit ('10GB'), or a bare number of kB
+    let value = unsafe { defGetString(option.as_ptr()) };
+    let mut value_kb = 0;
+    let mut hint = std::ptr::null();
+    let valid = unsafe { parse_int(value, &mut value_kb, GUC_UNIT_KB as _, &mut hint) };
+    if !valid || value_kb < 0 {
+        let value = unsafe { CStr::from_ptr(value) }.to_string_lossy();
+        pgrx::error!(
+            "invalid value for {} option: \"{}\"",
+            option_name.to_uppercase(),
+            value
+        );
+    }
+    let requested = value_kb as u64 * 1024;
+
+    let raises_limit = setting > 0 && (requested == 0 || requested > setting);
+    if raises_limit && !unsafe { superuser() } {
+        ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_INSUFFICIENT_PRIVILEGE,
+            format!("permission denied to raise {}", option_name.to_uppercase()),
+            format!(
+                "Only superusers may set {} above pigiaminja.{}.",
+                option_name.to_uppercase(),
+                option_name
+            )
+        );
+    }
+
+    requested
+}
+
 /// Get a COPY statement option by name
-fn copy_stmt_get_option(p_stmt: &PgBox<PlannedStmt>, option_name: &str) -> PgBox<DefElem> {
+pub(crate) fn copy_stmt_get_option(
+    p_stmt: &PgBox<PlannedStmt>,
+    option_name: &str,
+) -> PgBox<DefElem> {
     let copy_stmt = unsafe { PgBox::<CopyStmt>::from_pg(p_stmt.utilityStmt as _) };
 
     let copy_options = unsafe { PgList::<DefElem>::from_pg(copy_stmt.options) };
@@ -155,7 +385,7 @@ fn copy_stmt_get_option(p_stmt: &PgBox<PlannedStmt>, option_name: &str) -> PgBox
 }
 
 /// Check if the COPY statement specifies FORMAT jinja
-fn is_jinja_format_option(p_stmt: &PgBox<PlannedStmt>) -> bool {
+pub(crate) fn is_jinja_format_option(p_stmt: &PgBox<PlannedStmt>) -> bool {
     let format_option = copy_stmt_get_option(p_stmt, "format");
 
     if format_option.is_null() {
@@ -180,7 +410,8 @@ fn execute_copy_to_with_dest_receiver(
     params: &PgBox<ParamListInfoData>,
     query_env: &PgBox<QueryEnvironment>,
     jinja_dest: &PgBox<DestReceiver>,
-    is_stdout: bool,
+    limit_rows: i64,
+    progress_type: Option<u32>,
 ) -> i64 {
     unsafe {
         let copy_stmt = PgBox::<CopyStmt>::from_pg(p_stmt.utilityStmt as _);
@@ -205,11 +436,6 @@ fn execute_copy_to_with_dest_receiver(
             raw_query
         };
 
-        // Send COPY begin message (only for STDOUT)
-        if is_stdout {
-            send_copy_begin(1, false); // 1 column, text format
-        }
-
         // Analyze and rewrite the query
         let rewritten_queries = pg_analyze_and_rewrite(
             raw_query.as_ptr(),
@@ -253,11 +479,31 @@ fn execute_copy_to_with_dest_receiver(
             nprocessed: 0,
         };
 
+        // Show up in pg_stat_progress_copy like a native COPY TO, under the
+        // table for COPY table TO. Rows and bytes are updated by the receiver,
+        // and a COPY that fails is taken off the view by transaction abort.
+        // The receiver also reports the table's NOT NULL columns as such.
+        let relid = if copy_stmt.relation.is_null() {
+            Oid::INVALID
+        } else {
+            let rtable = PgList::<RangeTblEntry>::from_pg((*query).rtable);
+            rtable.get_ptr(0).map_or(Oid::INVALID, |rte| (*rte).relid)
+        };
+        (*(jinja_dest.as_ptr() as *mut JinjaDestReceiver)).set_source_relation(relid);
+        pgstat_progress_start_command(ProgressCommandType::PROGRESS_COMMAND_COPY, relid);
+        pgstat_progress_update_param(PROGRESS_COPY_COMMAND as _, PROGRESS_COPY_COMMAND_TO as _);
+        if let Some(progress_type) = progress_type {
+            pgstat_progress_update_param(PROGRESS_COPY_TYPE as _, progress_type as _);
+        }
+
+        // Fetch only LIMIT_ROWS rows when given
+        let count = if limit_rows > 0 { limit_rows } else { i64::MAX };
+
         // Execute the query with our custom DestReceiver
         #[cfg(any(feature = "pg14", feature = "pg15", feature = "pg16", feature = "pg17"))]
         PortalRun(
             portal.as_ptr(),
-            i64::MAX,
+            count as _,
             false,
             true,
             jinja_dest.as_ptr(),
@@ -269,17 +515,14 @@ fn execute_copy_to_with_dest_receiver(
         #[cfg(feature = "pg18")]
         PortalRun(
             portal.as_ptr(),
-            i64::MAX,
+            count as _,
             false,
             jinja_dest.as_ptr(),
             jinja_dest.as_ptr(),
             &mut completion_tag as _,
         );
 
-        // Send COPY end message (only for STDOUT)
-        if is_stdout {
-            send_copy_end();
-        }
+        pgstat_progress_end_command();
 
         PortalDrop(portal.as_ptr(), false);
 
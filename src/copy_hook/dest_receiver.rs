@@ -1,25 +1,54 @@
-use std::ffi::{c_char, CStr};
-use std::sync::Arc;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{c_char, c_int, c_void, CStr};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 
 use minijinja::value::{Enumerator, Object};
-use minijinja::{context, Environment, Value};
+use minijinja::{context, Environment, Template, Value};
 use pgrx::{
+    pg_sys::errcodes::PgSqlErrorCode,
     pg_sys::{
-        makeStringInfo, pfree, pq_beginmessage_reuse, pq_endmessage_reuse, resetStringInfo,
-        slot_getallattrs, AsPgCStr, BlessTupleDesc, CommandDest, CurrentMemoryContext, Datum,
-        DestReceiver, MemoryContext, StringInfoData, TupleDesc, TupleTableSlot,
+        ereport, makeStringInfo, pfree, pq_beginmessage_reuse, pq_endmessage_reuse,
+        resetStringInfo, slot_getallattrs, AsPgCStr, BlessTupleDesc, CommandDest,
+        CurrentMemoryContext, Datum, DestReceiver, MemoryContext, MemoryContextCallback,
+        MemoryContextRegisterResetCallback, MemoryContextReset, StringInfoData, TupleDesc,
+        TupleTableSlot,
     },
     prelude::*,
-    AllocatedByPostgres, FromDatum, PgBox, PgMemoryContexts, PgTupleDesc,
+    AllocatedByPostgres, FromDatum, GucSetting, PgBox, PgMemoryContexts, PgRelation, PgTupleDesc,
 };
 
+use super::binary_output::BinaryOutput;
+use super::globals::{add_session_functions, add_session_globals};
+use super::number_format::add_number_filters;
 use super::output::CopyDestination;
+use super::preset::{markdown_cell, xml_escape, Preset, FOOTER_BLOCK, HEADER_BLOCK, ROW_BLOCK};
+use super::query_function::add_query_function;
+use super::render_pool::{RenderPool, Rendered, RENDER_THREADS};
+use super::translations::add_translation_functions;
 
-const TEMPLATE_NAME: &str = "row";
+pub(super) const TEMPLATE_NAME: &str = "row";
+
+/// `pigiaminja.max_fuel`: per-row minijinja fuel, 0 meaning unlimited.
+pub static MAX_FUEL: GucSetting<i32> = GucSetting::<i32>::new(0);
+
+/// `pigiaminja.max_output_bytes`: cap on a whole export, in kB (0 = unlimited).
+pub static MAX_OUTPUT_BYTES: GucSetting<i32> = GucSetting::<i32>::new(0);
+
+/// `pigiaminja.max_row_output_bytes`: cap on one rendered row, in kB (0 = unlimited).
+pub static MAX_ROW_OUTPUT_BYTES: GucSetting<i32> = GucSetting::<i32>::new(0);
+
+/// `pigiaminja.copy_message_size`: how much output to collect into one CopyData
+/// message for COPY TO STDOUT, in kB (0 = one message per row).
+pub static COPY_MESSAGE_SIZE: GucSetting<i32> = GucSetting::<i32>::new(64);
+
+/// `pigiaminja.max_recursion`: minijinja recursion limit. 500 is both its
+/// default and the most it allows without the `stacker` feature.
+pub static MAX_RECURSION: GucSetting<i32> = GucSetting::<i32>::new(500);
 
 /// How to turn a column's datum into a minijinja value. Resolved once at startup
 /// from the column's type OID so the per-row hot path performs no catalog lookups.
-enum ColumnConv {
+pub(super) enum ColumnConv {
     Text,
     Int2,
     Int4,
@@ -36,31 +65,249 @@ enum ColumnConv {
     /// Fallback for any other type: call the type's text output function, whose
     /// lookup (`getTypeOutputInfo` + `fmgr_info`) is done once and cached here.
     Output { flinfo: pg_sys::FmgrInfo },
+    /// `bytea` (OID 17) under BINARY_OUTPUT: the raw bytes, printed verbatim.
+    Bytea,
+    /// A column the template never reads, left unconverted (and undetoasted).
+    Skip,
+}
+
+/// Output size caps for one COPY, in bytes (0 = unlimited). Resolved from the
+/// GUCs and COPY options before the receiver is created.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct OutputLimits {
+    pub max_output_bytes: u64,
+    pub max_row_output_bytes: u64,
+}
+
+/// The cap a row's render is running up against, for the error message.
+#[derive(Clone, Copy)]
+enum OutputLimit {
+    Row(u64),
+    Export(u64),
+}
+
+impl OutputLimit {
+    fn report(self) -> ! {
+        let (setting, limit) = match self {
+            OutputLimit::Row(limit) => ("max_row_output_bytes", limit),
+            OutputLimit::Export(limit) => ("max_output_bytes", limit),
+        };
+        ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_PROGRAM_LIMIT_EXCEEDED,
+            format!("jinja COPY output exceeds {} ({} bytes)", setting, limit),
+            format!(
+                "Raise pigiaminja.{} or the {} COPY option to allow larger output.",
+                setting,
+                setting.to_uppercase()
+            )
+        );
+    }
+}
+
+/// Where a timed COPY (DRY_RUN or LIMIT_ROWS) spent its time. Query
+/// execution is whatever is left once the receiver's own phases are taken out.
+#[derive(Default)]
+struct PhaseTimings {
+    started: Option<Instant>,
+    convert: Duration,
+    render: Duration,
+    write: Duration,
+}
+
+impl PhaseTimings {
+    fn report(&self, rows: u64, bytes: u64, written: bool) {
+        let total = self
+            .started
+            .map_or(Duration::ZERO, |started| started.elapsed());
+        let query = total.saturating_sub(self.convert + self.render + self.write);
+        let ms = |phase: Duration| format!("{:.3} ms", phase.as_secs_f64() * 1000.0);
+        let write = match written {
+            true => ms(self.write),
+            false => "skipped (DRY_RUN)".to_string(),
+        };
+
+        ereport!(
+            NOTICE,
+            PgSqlErrorCode::ERRCODE_SUCCESSFUL_COMPLETION,
+            format!(
+                "jinja COPY rendered {} rows ({} bytes) in {}",
+                rows,
+                bytes,
+                ms(total)
+            ),
+            format!(
+                "query execution: {}, datum conversion: {}, rendering: {}, writing: {}",
+                ms(query),
+                ms(self.convert),
+                ms(self.render),
+                write
+            )
+        );
+    }
+}
+
+/// The keys of the `row` map, shared by every row. Column names are not
+/// unique in a result (`SELECT a.id, b.id`), so a repeated name gets a `_2`,
+/// `_3`, ... suffix to keep each column reachable by name.
+#[derive(Debug)]
+pub(super) struct RowKeys {
+    names: Vec<Box<str>>,
+    positions: HashMap<Box<str>, usize>,
+}
+
+impl RowKeys {
+    pub fn new(column_names: Vec<Box<str>>) -> Self {
+        let mut names = Vec::with_capacity(column_names.len());
+        let mut positions = HashMap::with_capacity(column_names.len());
+
+        for (idx, name) in column_names.into_iter().enumerate() {
+            let mut key = name.clone();
+            let mut suffix = 1;
+            while positions.contains_key(&key) {
+                suffix += 1;
+                key = format!("{}_{}", name, suffix).into_boxed_str();
+            }
+            positions.insert(key.clone(), idx);
+            names.push(key);
+        }
+
+        RowKeys { names, positions }
+    }
+
+    pub fn names(&self) -> &[Box<str>] {
+        &self.names
+    }
+
+    /// A `row`-like value for these keys, readable by name and position
+    pub fn row(self: &Arc<Self>, values: Vec<Value>) -> Value {
+        Value::from_object(RowObject {
+            keys: self.clone(),
+            values: values.into_iter().map(OnceLock::from).collect(),
+            source: None,
+        })
+    }
+
+    /// A row whose cells are converted from the slot's `datums` the first
+    /// time the template reads them. See [`RowObject::detach`].
+    ///
+    /// # Safety
+    /// The datums must stay valid until the row is detached, and the row must
+    /// only be read on the backend thread.
+    unsafe fn lazy_row(
+        self: &Arc<Self>,
+        datums: &[Datum],
+        nulls: &[bool],
+        convs: *mut Vec<ColumnConv>,
+    ) -> Arc<RowObject> {
+        Arc::new(RowObject {
+            keys: self.clone(),
+            values: (0..datums.len()).map(|_| OnceLock::new()).collect(),
+            source: Some(RowSource {
+                datums: datums.iter().copied().zip(nulls.iter().copied()).collect(),
+                convs,
+            }),
+        })
+    }
+
+    /// The position of the column `key` names: a column name or an index
+    fn position(&self, key: &Value) -> Option<usize> {
+        match key.as_str() {
+            Some(name) => self.positions.get(name).copied(),
+            None => usize::try_from(key.as_i64()?).ok(),
+        }
+    }
+
+    fn enumerate(self: &Arc<Self>) -> Enumerator {
+        let keys = self.clone();
+        Enumerator::Iter(Box::new(
+            (0..keys.names.len()).map(move |i| Value::from(keys.names[i].as_ref())),
+        ))
+    }
 }
 
 /// A single output row exposed to the Jinja template as the `row` map (so the
-/// template can reference `row.<column>`). Holds the converted cell values plus
-/// the shared (Arc) column names. The per-row cost is one `Vec` + one `Arc`
-/// allocation, instead of building a serde_json map and re-serialising it into a
-/// minijinja value (two map representations) on every row.
+/// template can reference `row.<column>`, or `row[0]` by position). Holds the
+/// cell values plus the shared (Arc) keys. The per-row cost is one `Vec` + one
+/// `Arc` allocation, instead of building a serde_json map and re-serialising
+/// it into a minijinja value (two map representations) on every row.
+///
+/// A row rendered on the backend converts each cell the first time the
+/// template reads it, so a template that only shows some columns, or shows a
+/// big one only for some rows (`{% if row.flag %}{{ row.big_json }}{% endif %}`),
+/// converts and detoasts nothing else. Rows for render threads, and `query()`
+/// results, are converted up front.
 #[derive(Debug)]
 struct RowObject {
-    names: Arc<Vec<Box<str>>>,
-    values: Vec<Value>,
+    keys: Arc<RowKeys>,
+    values: Vec<OnceLock<Value>>,
+    source: Option<RowSource>,
+}
+
+/// Where a lazily converted row's cells come from: the executor's current
+/// tuple, readable only during the row's own render.
+struct RowSource {
+    datums: Vec<(Datum, bool)>,
+    convs: *mut Vec<ColumnConv>,
+}
+
+// Rows with a source are only created and read on the backend thread; the
+// ones sent to render threads have none.
+unsafe impl Send for RowObject {}
+unsafe impl Sync for RowObject {}
+
+impl std::fmt::Debug for RowSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RowSource").finish_non_exhaustive()
+    }
+}
+
+impl RowObject {
+    /// Called once the row's render is over, while its datums are still
+    /// valid. A template can hold on to the row past that (a `query()`
+    /// argument is cached, for one), so if anything still does, the cells it
+    /// has not read yet are converted now rather than never.
+    fn detach(self: &Arc<Self>) {
+        let Some(source) = &self.source else {
+            return;
+        };
+        if Arc::strong_count(self) == 1 {
+            return;
+        }
+        for (idx, cell) in self.values.iter().enumerate() {
+            cell.get_or_init(|| unsafe { source.convert(idx) });
+        }
+    }
+}
+
+impl RowSource {
+    /// # Safety
+    /// Only during the row's render, or from `detach`.
+    unsafe fn convert(&self, idx: usize) -> Value {
+        let (datum, is_null) = self.datums[idx];
+        let convs = &mut *self.convs;
+        convert_cell(datum, is_null, &mut convs[idx])
+    }
 }
 
 impl Object for RowObject {
     fn get_value(self: &Arc<Self>, key: &Value) -> Option<Value> {
-        let key = key.as_str()?;
-        let idx = self.names.iter().position(|name| name.as_ref() == key)?;
-        Some(self.values[idx].clone())
+        let idx = self.keys.position(key)?;
+        let cell = self.values.get(idx)?;
+        let source = self.source.as_ref();
+        // Every cell is set for a row without a source, or a detached one
+        // that is still referenced
+        let value =
+            cell.get_or_init(|| unsafe { source.map_or(Value::UNDEFINED, |s| s.convert(idx)) });
+        Some(value.clone())
     }
 
     fn enumerate(self: &Arc<Self>) -> Enumerator {
-        let names = self.names.clone();
-        Enumerator::Iter(Box::new(
-            (0..names.len()).map(move |i| Value::from(names[i].as_ref())),
-        ))
+        self.keys.enumerate()
+    }
+
+    fn enumerator_len(self: &Arc<Self>) -> Option<usize> {
+        Some(self.values.len())
     }
 }
 
@@ -69,21 +316,165 @@ pub(crate) struct JinjaDestReceiver {
     dest: DestReceiver,
     natts: usize,
     tupledesc: TupleDesc,
-    env: *mut Environment<'static>,
+    /// Shared with the render threads, if any.
+    env: *mut Arc<Environment<'static>>,
     template_string: *mut String,
     /// Where rendered rows go: stdout (wire protocol), a file, or a program's stdin.
     output_destination: *mut CopyDestination,
     memory_context: MemoryContext,
-    /// Shared column names, Arc-cloned into each row (never re-allocated per row).
-    column_names: *mut Arc<Vec<Box<str>>>,
+    /// Child of `memory_context` that a row's conversion and render allocate
+    /// in (output function results, detoasted values, SQL filter calls),
+    /// reset after every row so a long export does not grow.
+    row_context: MemoryContext,
+    /// Shared row keys, Arc-cloned into each row (never re-allocated per row).
+    row_keys: *mut Arc<RowKeys>,
     /// Per-column datum converters, resolved once at startup.
     column_convs: *mut Vec<ColumnConv>,
+    /// Threads rendering rows, when pigiaminja.render_threads is above 1.
+    render_pool: *mut RenderPool,
     /// Reusable StringInfo buffer for COPY data messages (avoids per-row allocation).
+    /// Allocated in `memory_context`, so it goes away with it on abort.
     copy_buf: *mut StringInfoData,
+    /// For STDOUT, whether `copy_buf` holds a CopyData message still being
+    /// filled, and the size at which it is sent.
+    message_open: bool,
+    message_size: u64,
+    /// BINARY_OUTPUT: carries `bytea` values out as raw bytes. Null when off.
+    binary_output: *mut BinaryOutput,
+    /// The COPY's options (other than the template), exposed as `options`.
+    copy_options: *mut Vec<(String, String)>,
+    /// The PARAMS option, exposed as `params`.
+    params: *mut Value,
+    /// Rows rendered so far, for `rownum` / `is_first`.
+    rows_processed: u64,
+    /// Built-in template the COPY renders (or extends), if PRESET was given.
+    /// Its header, row and footer blocks are then rendered separately.
+    preset: Option<Preset>,
+    /// Output size caps, and the bytes rendered so far across all rows.
+    limits: OutputLimits,
+    bytes_written: u64,
+    /// Per-phase timings, collected for DRY_RUN / LIMIT_ROWS.
+    timings: Option<PhaseTimings>,
+    /// The table of a COPY table TO, whose NOT NULL columns `columns` reports.
+    /// Invalid for a query, whose result columns Postgres does not track that
+    /// for.
+    source_relation: pg_sys::Oid,
+    /// Reset callback on `memory_context`, releasing the Rust-side state if the
+    /// COPY is aborted by an ERROR before `jinja_shutdown` gets to run.
+    reset_callback: MemoryContextCallback,
 }
 
 impl JinjaDestReceiver {
+    /// Time the COPY's phases and report them in a NOTICE when it is done.
+    /// Rows are then all rendered on the backend, so the phases do not overlap.
+    pub(crate) fn collect_timings(&mut self) {
+        self.timings = Some(PhaseTimings::default());
+    }
+
+    /// Report the columns of `relid` declared NOT NULL as not nullable.
+    pub(crate) fn set_source_relation(&mut self, relid: pg_sys::Oid) {
+        self.source_relation = relid;
+    }
+
+    /// Free the boxed Rust state hanging off the receiver. Shared by the normal
+    /// shutdown path and the abort callback; safe to call more than once.
+    ///
+    /// # Safety
+    /// The pointers must either be null or come from `Box::into_raw`.
+    unsafe fn release_resources(&mut self) {
+        // First, so the render threads are gone before the rest goes away
+        if !self.render_pool.is_null() {
+            let _ = Box::from_raw(self.render_pool);
+            self.render_pool = std::ptr::null_mut();
+        }
+
+        if !self.env.is_null() {
+            let _ = Box::from_raw(self.env);
+            self.env = std::ptr::null_mut();
+        }
+
+        if !self.template_string.is_null() {
+            let _ = Box::from_raw(self.template_string);
+            self.template_string = std::ptr::null_mut();
+        }
+
+        if !self.row_keys.is_null() {
+            let _ = Box::from_raw(self.row_keys);
+            self.row_keys = std::ptr::null_mut();
+        }
+
+        if !self.column_convs.is_null() {
+            let _ = Box::from_raw(self.column_convs);
+            self.column_convs = std::ptr::null_mut();
+        }
+
+        if !self.copy_options.is_null() {
+            let _ = Box::from_raw(self.copy_options);
+            self.copy_options = std::ptr::null_mut();
+        }
+
+        if !self.params.is_null() {
+            let _ = Box::from_raw(self.params);
+            self.params = std::ptr::null_mut();
+        }
+
+        if !self.binary_output.is_null() {
+            let _ = Box::from_raw(self.binary_output);
+            self.binary_output = std::ptr::null_mut();
+        }
+    }
+
+    /// How many bytes the next row may render before hitting a cap, and which
+    /// cap that is. `None` when output is unlimited.
+    fn row_budget(&self) -> Option<(u64, OutputLimit)> {
+        let row = (self.limits.max_row_output_bytes > 0).then(|| {
+            let limit = self.limits.max_row_output_bytes;
+            (limit, OutputLimit::Row(limit))
+        });
+        let export = (self.limits.max_output_bytes > 0).then(|| {
+            let limit = self.limits.max_output_bytes;
+            (
+                limit.saturating_sub(self.bytes_written),
+                OutputLimit::Export(limit),
+            )
+        });
+
+        match (row, export) {
+            (Some(row), Some(export)) => Some(if export.0 < row.0 { export } else { row }),
+            (row, export) => row.or(export),
+        }
+    }
+
     fn process_tuple(&mut self, slot: *mut TupleTableSlot) {
+        // Let pg_cancel_backend / statement_timeout stop the export between rows
+        pg_sys::check_for_interrupts!();
+
+        let row_context = self.row_context;
+        unsafe {
+            PgMemoryContexts::For(row_context).switch_to(|_| self.convert_and_render(slot));
+            MemoryContextReset(row_context);
+        }
+        self.report_progress();
+    }
+
+    /// Publish the rows and bytes so far to pg_stat_progress_copy. Bytes
+    /// still with the render threads are counted once they come back.
+    fn report_progress(&self) {
+        let index = [
+            pg_sys::PROGRESS_COPY_TUPLES_PROCESSED as c_int,
+            pg_sys::PROGRESS_COPY_BYTES_PROCESSED as c_int,
+        ];
+        let values = [self.rows_processed as i64, self.bytes_written as i64];
+        unsafe {
+            pg_sys::pgstat_progress_update_multi_param(
+                index.len() as c_int,
+                index.as_ptr(),
+                values.as_ptr(),
+            );
+        }
+    }
+
+    fn convert_and_render(&mut self, slot: *mut TupleTableSlot) {
         unsafe {
             // Extract all attributes from the slot
             slot_getallattrs(slot);
@@ -92,38 +483,113 @@ impl JinjaDestReceiver {
             let datums = std::slice::from_raw_parts((*slot).tts_values, natts);
             let nulls = std::slice::from_raw_parts((*slot).tts_isnull, natts);
 
-            let convs = &mut *self.column_convs;
-            let names = &*self.column_names;
+            let keys = &*self.row_keys;
+            self.rows_processed += 1;
+
+            match self.render_pool.as_mut() {
+                Some(pool) if !pool.is_serial() => {
+                    // Render threads cannot convert datums, so they get the
+                    // whole row converted up front
+                    let values = self.convert_row(datums, nulls);
+                    pool.push(keys.row(values), self.rows_processed);
+                    self.emit_rendered(pool, false);
+                }
+                None if self.timings.is_some() => {
+                    // Converted up front too, so conversion is not timed as
+                    // part of rendering
+                    let started = Instant::now();
+                    let values = self.convert_row(datums, nulls);
+                    if let Some(timings) = &mut self.timings {
+                        timings.convert += started.elapsed();
+                    }
+                    self.render(keys.row(values), self.rows_processed);
+                }
+                pool => {
+                    if let Some(pool) = pool {
+                        pool.flush();
+                        self.emit_rendered(pool, true);
+                    }
 
-            let mut values = Vec::with_capacity(natts);
-            for (idx, (datum, is_null)) in datums.iter().zip(nulls).enumerate() {
-                values.push(if *is_null {
-                    Value::from(())
-                } else {
-                    convert_datum(*datum, &mut convs[idx])
-                });
+                    let row = keys.lazy_row(datums, nulls, self.column_convs);
+                    self.render(Value::from_dyn_object(row.clone()), self.rows_processed);
+                    row.detach();
+                }
             }
+        }
+    }
 
-            let row = Value::from_object(RowObject {
-                names: names.clone(),
-                values,
-            });
+    /// Convert a whole row, leaving out the columns the template never reads.
+    ///
+    /// # Safety
+    /// `datums` and `nulls` must be the current slot's values.
+    unsafe fn convert_row(&mut self, datums: &[Datum], nulls: &[bool]) -> Vec<Value> {
+        let convs = &mut *self.column_convs;
+        datums
+            .iter()
+            .zip(nulls)
+            .zip(convs.iter_mut())
+            .map(|((datum, is_null), conv)| convert_cell(*datum, *is_null, conv))
+            .collect()
+    }
 
-            // Use pre-compiled template instead of render_str (which recompiles per row)
-            let env = self
-                .env
-                .as_ref()
-                .expect("Jinja environment not initialized");
+    /// Render a row on the backend.
+    fn render(&mut self, row: Value, rownum: u64) {
+        // Use pre-compiled template instead of render_str (which recompiles per row)
+        let env = unsafe { self.env.as_ref() }.expect("Jinja environment not initialized");
 
-            let template = env
-                .get_template(TEMPLATE_NAME)
-                .expect("Pre-compiled template not found");
+        let template = env
+            .get_template(TEMPLATE_NAME)
+            .expect("Pre-compiled template not found");
+
+        let preset = self.preset.is_some();
+        self.emit(|writer| render_row(&template, preset, row, rownum, writer));
+    }
+
+    /// Send on what the render threads have finished, in row order. With
+    /// `wait`, everything queued so far.
+    fn emit_rendered(&mut self, pool: &mut RenderPool, wait: bool) {
+        while let Some(rendered) = pool.next(wait) {
+            match rendered {
+                Rendered::Output(output) => self.emit(|mut writer| {
+                    writer.append(&output);
+                    Ok(())
+                }),
+                Rendered::Failed(e) => pgrx::error!("Failed to render Jinja template: {}", e),
+                Rendered::TooLarge => {
+                    let (_, limit) = self
+                        .row_budget()
+                        .expect("row output was capped without a limit");
+                    limit.report();
+                }
+                Rendered::NeedsBackend { row, rownum } => self.render(row, rownum),
+            }
+        }
+    }
 
+    /// Render a preset's header or footer block.
+    fn emit_block(&mut self, block: &str) {
+        let env = unsafe { self.env.as_ref() }.expect("Jinja environment not initialized");
+
+        let template = env
+            .get_template(TEMPLATE_NAME)
+            .expect("Pre-compiled template not found");
+
+        self.emit(|writer| {
+            template
+                .eval_to_state(context! {})?
+                .render_block_to_write(block, writer)
+        });
+    }
+
+    /// Run `render` against the output buffer and send the result on.
+    fn emit(&mut self, render: impl FnOnce(StringInfoWriter) -> Result<(), minijinja::Error>) {
+        unsafe {
             // Render directly into the reused buffer. Avoids a per-row output
             // String allocation plus an extra full-row copy. For STDOUT the
             // buffer is the wire message itself, framed by the pq_*_reuse
-            // calls; for file/program destinations it is scratch space whose
-            // payload is handed to the CopyDestination.
+            // calls and sent once it holds copy_message_size worth of rows;
+            // for file/program destinations it is scratch space whose payload
+            // is handed to the CopyDestination.
             let destination = self
                 .output_destination
                 .as_mut()
@@ -131,46 +597,142 @@ impl JinjaDestReceiver {
 
             let buf = self.copy_buf;
             if destination.is_stdout() {
-                pq_beginmessage_reuse(buf, b'd' as _);
+                if !self.message_open {
+                    pq_beginmessage_reuse(buf, b'd' as _);
+                    self.message_open = true;
+                }
             } else {
                 resetStringInfo(buf);
             }
-            if let Err(e) =
-                template.render_to_write(context! { row => row }, StringInfoWriter(buf))
-            {
+            let start = (*buf).len;
+            let render_started = self.timings.is_some().then(Instant::now);
+            let writer = StringInfoWriter {
+                buf,
+                budget: self.row_budget(),
+                binary_output: self.binary_output.as_ref().cloned(),
+            };
+            if let Err(e) = render(writer) {
                 pgrx::error!("Failed to render Jinja template: {}", e);
             }
+            if !self.binary_output.is_null() {
+                BinaryOutput::finish_row();
+            }
+            self.bytes_written += ((*buf).len - start) as u64;
+            if let (Some(render_started), Some(timings)) = (render_started, &mut self.timings) {
+                timings.render += render_started.elapsed();
+            }
             if destination.is_stdout() {
-                pq_endmessage_reuse(buf);
+                if (*buf).len as u64 >= self.message_size {
+                    self.send_message();
+                }
             } else {
+                let write_started = self.timings.is_some().then(Instant::now);
                 let data =
                     std::slice::from_raw_parts((*buf).data as *const u8, (*buf).len as usize);
                 if let Err(e) = destination.write_data(data) {
                     pgrx::error!("Failed to write COPY data: {}", e);
                 }
+                if let (Some(write_started), Some(timings)) = (write_started, &mut self.timings) {
+                    timings.write += write_started.elapsed();
+                }
+            }
+        }
+    }
+
+    /// Send the CopyData message being filled, if it has anything in it.
+    fn send_message(&mut self) {
+        if !self.message_open {
+            return;
+        }
+        self.message_open = false;
+
+        let started = self.timings.is_some().then(Instant::now);
+        unsafe {
+            if (*self.copy_buf).len > 0 {
+                pq_endmessage_reuse(self.copy_buf);
             }
         }
+        if let (Some(started), Some(timings)) = (started, &mut self.timings) {
+            timings.write += started.elapsed();
+        }
+    }
+}
+
+/// Render one row of `template`: the whole template, or a preset's row block.
+pub(super) fn render_row(
+    template: &Template,
+    preset: bool,
+    row: Value,
+    rownum: u64,
+    out: impl std::io::Write,
+) -> Result<(), minijinja::Error> {
+    let ctx = context! {
+        row => row,
+        rownum => rownum,
+        is_first => rownum == 1,
+    };
+    if preset {
+        template
+            .eval_to_state(ctx)?
+            .render_block_to_write(ROW_BLOCK, out)
+    } else {
+        template.render_to_write(ctx, out).map(|_| ())
     }
 }
 
 /// `std::io::Write` adapter that appends bytes straight into a Postgres
 /// `StringInfo`, letting the template render directly into the COPY send buffer.
-struct StringInfoWriter(*mut StringInfoData);
+/// It also checks for interrupts, so a single huge row can still be cancelled,
+/// and enforces the output caps before the bytes ever reach the destination.
+struct StringInfoWriter {
+    buf: *mut StringInfoData,
+    /// Bytes this row may still append, and the cap that imposes it.
+    budget: Option<(u64, OutputLimit)>,
+    /// With BINARY_OUTPUT, puts printed `bytea` values back as raw bytes.
+    binary_output: Option<BinaryOutput>,
+}
 
-impl std::io::Write for StringInfoWriter {
+impl StringInfoWriter {
+    /// Append output as is: rendered on a render thread, or already restored.
     #[inline]
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+    fn append(&mut self, bytes: &[u8]) {
+        pg_sys::check_for_interrupts!();
+        if let Some((remaining, limit)) = &mut self.budget {
+            if bytes.len() as u64 > *remaining {
+                limit.report();
+            }
+            *remaining -= bytes.len() as u64;
+        }
         unsafe {
-            pg_sys::appendBinaryStringInfo(self.0, buf.as_ptr() as *const _, buf.len() as _);
+            pg_sys::appendBinaryStringInfo(self.buf, bytes.as_ptr() as *const _, bytes.len() as _);
         }
+    }
+}
+
+impl StringInfoWriter {
+    /// Append what the template rendered.
+    #[inline]
+    fn write_rendered(&mut self, buf: &[u8]) {
+        match &self.binary_output {
+            Some(binary_output) => {
+                let bytes = binary_output.restore(buf);
+                self.append(&bytes);
+            }
+            None => self.append(buf),
+        }
+    }
+}
+
+impl std::io::Write for StringInfoWriter {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.write_rendered(buf);
         Ok(buf.len())
     }
 
     #[inline]
     fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
-        unsafe {
-            pg_sys::appendBinaryStringInfo(self.0, buf.as_ptr() as *const _, buf.len() as _);
-        }
+        self.write_rendered(buf);
         Ok(())
     }
 
@@ -180,12 +742,24 @@ impl std::io::Write for StringInfoWriter {
     }
 }
 
+/// Convert a cell of a row: NULL is none, a skipped column undefined.
+///
+/// # Safety
+/// As for `convert_datum`.
+unsafe fn convert_cell(datum: Datum, is_null: bool, conv: &mut ColumnConv) -> Value {
+    match conv {
+        ColumnConv::Skip => Value::UNDEFINED,
+        _ if is_null => Value::from(()),
+        conv => convert_datum(datum, conv),
+    }
+}
+
 /// Convert a single non-null `datum` into a minijinja value using the
 /// precomputed converter for its column.
 ///
 /// # Safety
 /// `datum` must be a valid datum of the column type `conv` was built for.
-unsafe fn convert_datum(datum: Datum, conv: &mut ColumnConv) -> Value {
+pub(super) unsafe fn convert_datum(datum: Datum, conv: &mut ColumnConv) -> Value {
     match conv {
         // Borrow the text out of the datum (detoasted) and let minijinja inline
         // short strings as SmallStr; no intermediate String allocation.
@@ -227,6 +801,9 @@ unsafe fn convert_datum(datum: Datum, conv: &mut ColumnConv) -> Value {
                 }
             }
         }
+        ColumnConv::Bytea => <&[u8]>::from_datum(datum, false)
+            .map_or(Value::from(()), |b| Value::from_bytes(b.to_vec())),
+        ColumnConv::Skip => Value::UNDEFINED,
     }
 }
 
@@ -235,7 +812,7 @@ unsafe fn convert_datum(datum: Datum, conv: &mut ColumnConv) -> Value {
 ///
 /// # Safety
 /// Must run inside a Postgres backend (performs catalog lookups).
-unsafe fn column_conv_for(type_oid: u32, memory_context: MemoryContext) -> ColumnConv {
+pub(super) unsafe fn column_conv_for(type_oid: u32, memory_context: MemoryContext) -> ColumnConv {
     match type_oid {
         // Text types: TEXTOID | VARCHAROID | BPCHAROID | NAMEOID
         25 | 1043 | 1042 | 19 => ColumnConv::Text,
@@ -264,6 +841,110 @@ unsafe fn column_conv_for(type_oid: u32, memory_context: MemoryContext) -> Colum
     }
 }
 
+/// Which columns `template` reads, going by the `row.<column>` paths it
+/// references. None when it uses `row` as a whole (`row[0]`, `row|items`,
+/// passing it to a macro), which could read any of them.
+fn used_columns(template: &Template, keys: &RowKeys) -> Option<Vec<bool>> {
+    let mut used = vec![false; keys.names.len()];
+    for variable in template.undeclared_variables(true) {
+        if variable == "row" {
+            return None;
+        }
+        let Some(path) = variable.strip_prefix("row.") else {
+            continue;
+        };
+        let column = path.split('.').next().unwrap_or_default();
+        if let Some(idx) = keys.positions.get(column) {
+            used[*idx] = true;
+        }
+    }
+    Some(used)
+}
+
+/// Describe a result column for the `columns` global, so generic templates
+/// can format values by type without knowing the query. `name` is the
+/// column's key in `row`.
+///
+/// # Safety
+/// Must run inside a Postgres backend (looks up the type name).
+unsafe fn column_metadata(
+    attribute: &pg_sys::FormData_pg_attribute,
+    name: &str,
+    idx: usize,
+    nullable: bool,
+) -> Value {
+    let type_name = CStr::from_ptr(pg_sys::format_type_be(attribute.atttypid))
+        .to_string_lossy()
+        .into_owned();
+
+    context! {
+        name => name,
+        type_name => type_name,
+        type_oid => u32::from(attribute.atttypid),
+        typmod => attribute.atttypmod,
+        nullable => nullable,
+        position => idx + 1,
+    }
+}
+
+/// Which result columns are NOT NULL columns of `relid`, matched by name as
+/// COPY table TO selects them. All false without a relation.
+///
+/// # Safety
+/// Must run inside a Postgres backend, with `relid` locked if valid.
+unsafe fn not_null_columns(relid: pg_sys::Oid, tupledesc: &PgTupleDesc) -> Vec<bool> {
+    if relid == pg_sys::Oid::INVALID {
+        return vec![false; tupledesc.len()];
+    }
+
+    let relation = PgRelation::open(relid);
+    let table_desc = relation.tuple_desc();
+    tupledesc
+        .iter()
+        .map(|attribute| {
+            let attnum = pg_sys::get_attnum(relid, attribute.attname.data.as_ptr());
+            attnum > 0
+                && table_desc
+                    .get(attnum as usize - 1)
+                    .is_some_and(|column| column.attnotnull)
+        })
+        .collect()
+}
+
+/// Globals describing the export as a whole: when the statement started, who
+/// runs it, in which database, and the COPY options it was given.
+///
+/// # Safety
+/// Must run inside a Postgres backend (looks up the role and database names).
+unsafe fn export_globals(copy_options: &[(String, String)]) -> [(&'static str, Value); 4] {
+    // As JSON encodes it: ISO 8601 whatever the DateStyle
+    let query_started_at = CStr::from_ptr(pg_sys::JsonEncodeDateTime(
+        std::ptr::null_mut(),
+        Datum::from(pg_sys::GetCurrentStatementStartTimestamp()),
+        pg_sys::TIMESTAMPTZOID,
+        std::ptr::null(),
+    ))
+    .to_string_lossy()
+    .into_owned();
+    let current_user = CStr::from_ptr(pg_sys::GetUserNameFromId(pg_sys::GetUserId(), false))
+        .to_string_lossy()
+        .into_owned();
+    let database = CStr::from_ptr(pg_sys::get_database_name(pg_sys::MyDatabaseId))
+        .to_string_lossy()
+        .into_owned();
+    let options: BTreeMap<&str, &str> = copy_options
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .collect();
+
+    [
+        ("query_started_at", Value::from(query_started_at)),
+        ("current_user", Value::from(current_user)),
+        ("database", Value::from(database)),
+        ("options", Value::from_serialize(options)),
+    ]
+}
+
 #[pg_guard]
 pub(crate) extern "C-unwind" fn jinja_startup(
     dest: *mut DestReceiver,
@@ -282,31 +963,130 @@ pub(crate) extern "C-unwind" fn jinja_startup(
         let tupledesc = PgTupleDesc::from_pg_unchecked(jinja_dest.tupledesc);
         jinja_dest.natts = tupledesc.len();
 
-        // Cache per-column name + converter once. This pulls the type OID and
-        // (for fallback types) the output function out of the per-row loop.
-        let mut names = Vec::with_capacity(jinja_dest.natts);
-        let mut convs = Vec::with_capacity(jinja_dest.natts);
-        for idx in 0..jinja_dest.natts {
-            let attribute = tupledesc.get(idx).expect("cannot get attribute");
-            let type_oid: u32 = attribute.type_oid().value().into();
-            names.push(attribute.name().to_string().into_boxed_str());
-            convs.push(column_conv_for(type_oid, jinja_dest.memory_context));
-        }
-        jinja_dest.column_names = Box::into_raw(Box::new(Arc::new(names)));
-        jinja_dest.column_convs = Box::into_raw(Box::new(convs));
+        let names = tupledesc
+            .iter()
+            .map(|attribute| attribute.name().to_string().into_boxed_str())
+            .collect();
+        let keys = RowKeys::new(names);
+        let not_null = not_null_columns(jinja_dest.source_relation, &tupledesc);
+        let columns: Vec<Value> = tupledesc
+            .iter()
+            .zip(keys.names())
+            .enumerate()
+            .map(|(idx, (attribute, key))| column_metadata(attribute, key, idx, !not_null[idx]))
+            .collect();
+        jinja_dest.row_keys = Box::into_raw(Box::new(Arc::new(keys)));
 
         // Pre-allocate reusable StringInfo buffer for COPY data messages
-        jinja_dest.copy_buf = makeStringInfo();
+        jinja_dest.copy_buf =
+            PgMemoryContexts::For(jinja_dest.memory_context).switch_to(|_| makeStringInfo());
+
+        jinja_dest.row_context = pg_sys::AllocSetContextCreateExtended(
+            jinja_dest.memory_context,
+            "Jinja Row Context".as_pg_cstr(),
+            pg_sys::ALLOCSET_DEFAULT_MINSIZE as _,
+            pg_sys::ALLOCSET_DEFAULT_INITSIZE as _,
+            pg_sys::ALLOCSET_DEFAULT_MAXSIZE as _,
+        );
 
         // Initialize Jinja environment and pre-compile the template
         let mut ctx = PgMemoryContexts::For(jinja_dest.memory_context);
         ctx.switch_to(|_context| {
             let template_string = &*jinja_dest.template_string;
             let mut env = Environment::new();
+
+            // Bound what a single row's render may do
+            let max_fuel = MAX_FUEL.get();
+            if max_fuel > 0 {
+                env.set_fuel(Some(max_fuel as u64));
+            }
+            env.set_recursion_limit(MAX_RECURSION.get() as usize);
+
+            add_session_globals(&mut env);
+            add_session_functions(&mut env, jinja_dest.memory_context);
+            add_query_function(&mut env);
+            add_number_filters(&mut env);
+            let locale = (*jinja_dest.copy_options)
+                .iter()
+                .find(|(name, _)| name == "locale")
+                .map(|(_, value)| value.as_str());
+            add_translation_functions(&mut env, locale);
+            env.add_global("columns", Value::from(columns));
+            for (name, value) in export_globals(&*jinja_dest.copy_options) {
+                env.add_global(name, value);
+            }
+            env.add_global("params", (*jinja_dest.params).clone());
+            if let Some(binary_output) = jinja_dest.binary_output.as_ref() {
+                binary_output.add_to(&mut env);
+            }
+
+            if let Some(preset) = jinja_dest.preset {
+                env.add_filter("markdown_cell", markdown_cell);
+                env.add_filter("xml_escape", xml_escape);
+                env.add_template_owned(
+                    preset.name(),
+                    preset.template_source((*jinja_dest.row_keys).names()),
+                )
+                .unwrap_or_else(|e| pgrx::error!("Failed to compile Jinja template: {}", e));
+            }
+
             env.add_template_owned(TEMPLATE_NAME.to_owned(), template_string.clone())
                 .unwrap_or_else(|e| pgrx::error!("Failed to compile Jinja template: {}", e));
+            // Cache per-column converters once. This pulls the type OID and
+            // (for fallback types) the output function out of the per-row loop.
+            let template = env
+                .get_template(TEMPLATE_NAME)
+                .expect("Pre-compiled template not found");
+            let used = match jinja_dest.preset {
+                Some(_) => None,
+                None => used_columns(&template, &*jinja_dest.row_keys),
+            };
+            let convs: Vec<ColumnConv> = tupledesc
+                .iter()
+                .enumerate()
+                .map(|(idx, attribute)| {
+                    if used.as_ref().is_some_and(|used| !used[idx]) {
+                        return ColumnConv::Skip;
+                    }
+                    let type_oid: u32 = attribute.type_oid().value().into();
+                    if !jinja_dest.binary_output.is_null()
+                        && type_oid == u32::from(pg_sys::BYTEAOID)
+                    {
+                        return ColumnConv::Bytea;
+                    }
+                    column_conv_for(type_oid, jinja_dest.memory_context)
+                })
+                .collect();
+            jinja_dest.column_convs = Box::into_raw(Box::new(convs));
+
+            let env = Arc::new(env);
+
+            let threads = RENDER_THREADS.get() as usize;
+            if threads > 1 && jinja_dest.timings.is_none() {
+                let limits = jinja_dest.limits;
+                let output_cap = [limits.max_row_output_bytes, limits.max_output_bytes]
+                    .into_iter()
+                    .filter(|limit| *limit > 0)
+                    .min();
+                let pool = RenderPool::new(
+                    env.clone(),
+                    threads,
+                    jinja_dest.preset.is_some(),
+                    output_cap,
+                    jinja_dest.binary_output.as_ref().cloned(),
+                );
+                jinja_dest.render_pool = Box::into_raw(Box::new(pool));
+            }
             jinja_dest.env = Box::into_raw(Box::new(env));
         });
+
+        if let Some(timings) = &mut jinja_dest.timings {
+            timings.started = Some(Instant::now());
+        }
+
+        if jinja_dest.preset.is_some() {
+            jinja_dest.emit_block(HEADER_BLOCK);
+        }
     }
 }
 
@@ -334,27 +1114,27 @@ pub(crate) extern "C-unwind" fn jinja_shutdown(dest: *mut DestReceiver) {
             .expect("invalid jinja dest receiver ptr")
     };
 
-    // Clean up allocated memory
-    unsafe {
-        if !jinja_dest.env.is_null() {
-            let _ = Box::from_raw(jinja_dest.env);
-            jinja_dest.env = std::ptr::null_mut();
-        }
+    // Rows still with the render threads
+    if let Some(pool) = unsafe { jinja_dest.render_pool.as_mut() } {
+        pool.flush();
+        jinja_dest.emit_rendered(pool, true);
+    }
 
-        if !jinja_dest.template_string.is_null() {
-            let _ = Box::from_raw(jinja_dest.template_string);
-            jinja_dest.template_string = std::ptr::null_mut();
-        }
+    if jinja_dest.preset.is_some() {
+        jinja_dest.emit_block(FOOTER_BLOCK);
+    }
+    jinja_dest.send_message();
+    jinja_dest.report_progress();
 
-        if !jinja_dest.column_names.is_null() {
-            let _ = Box::from_raw(jinja_dest.column_names);
-            jinja_dest.column_names = std::ptr::null_mut();
-        }
+    if let Some(timings) = &jinja_dest.timings {
+        let written = unsafe { jinja_dest.output_destination.as_ref() }
+            .is_some_and(|destination| !destination.is_discard());
+        timings.report(jinja_dest.rows_processed, jinja_dest.bytes_written, written);
+    }
 
-        if !jinja_dest.column_convs.is_null() {
-            let _ = Box::from_raw(jinja_dest.column_convs);
-            jinja_dest.column_convs = std::ptr::null_mut();
-        }
+    // Clean up allocated memory
+    unsafe {
+        jinja_dest.release_resources();
 
         if !jinja_dest.copy_buf.is_null() {
             pfree((*jinja_dest.copy_buf).data as _);
@@ -364,14 +1144,38 @@ pub(crate) extern "C-unwind" fn jinja_shutdown(dest: *mut DestReceiver) {
 
         if !jinja_dest.output_destination.is_null() {
             let mut destination = Box::from_raw(jinja_dest.output_destination);
+            jinja_dest.output_destination = std::ptr::null_mut();
             if let Err(e) = destination.finalize() {
-                pgrx::warning!("Failed to finalize output destination: {}", e);
+                pgrx::error!("Failed to finalize output destination: {}", e);
             }
-            jinja_dest.output_destination = std::ptr::null_mut();
         }
     }
 }
 
+/// Reset callback registered on the receiver's memory context. After a normal
+/// `jinja_shutdown` everything is already released and this is a no-op. When an
+/// ERROR aborts the COPY (mid-render, or even before the executor started the
+/// receiver) shutdown never runs, and the context being deleted during
+/// transaction abort is our only chance to free the Rust-side state and reap a
+/// COPY TO PROGRAM child.
+#[pg_guard]
+unsafe extern "C-unwind" fn jinja_reset_callback(arg: *mut c_void) {
+    let jinja_dest = (arg as *mut JinjaDestReceiver)
+        .as_mut()
+        .expect("invalid jinja dest receiver ptr");
+
+    jinja_dest.release_resources();
+
+    // The buffer lives in the context being reset; just forget it.
+    jinja_dest.copy_buf = std::ptr::null_mut();
+
+    if !jinja_dest.output_destination.is_null() {
+        let mut destination = Box::from_raw(jinja_dest.output_destination);
+        jinja_dest.output_destination = std::ptr::null_mut();
+        destination.abort();
+    }
+}
+
 #[pg_guard]
 pub(crate) extern "C-unwind" fn jinja_destroy(_dest: *mut DestReceiver) {}
 
@@ -380,6 +1184,11 @@ pub(crate) extern "C-unwind" fn jinja_destroy(_dest: *mut DestReceiver) {}
 pub(crate) extern "C-unwind" fn create_jinja_dest_receiver(
     template_content: *const c_char,
     output_destination: *mut CopyDestination,
+    limits: OutputLimits,
+    preset: Option<Preset>,
+    copy_options: Vec<(String, String)>,
+    params: Value,
+    binary_output: bool,
 ) -> *mut JinjaDestReceiver {
     let memory_context = unsafe {
         pg_sys::AllocSetContextCreateExtended(
@@ -413,9 +1222,36 @@ pub(crate) extern "C-unwind" fn create_jinja_dest_receiver(
     jinja_dest.template_string = Box::into_raw(Box::new(template_string));
     jinja_dest.output_destination = output_destination;
     jinja_dest.memory_context = memory_context;
-    jinja_dest.column_names = std::ptr::null_mut();
+    jinja_dest.row_context = std::ptr::null_mut();
+    jinja_dest.row_keys = std::ptr::null_mut();
     jinja_dest.column_convs = std::ptr::null_mut();
+    jinja_dest.render_pool = std::ptr::null_mut();
     jinja_dest.copy_buf = std::ptr::null_mut();
+    jinja_dest.message_open = false;
+    jinja_dest.message_size = COPY_MESSAGE_SIZE.get() as u64 * 1024;
+    jinja_dest.binary_output = if binary_output {
+        Box::into_raw(Box::new(BinaryOutput::new()))
+    } else {
+        std::ptr::null_mut()
+    };
+    jinja_dest.copy_options = Box::into_raw(Box::new(copy_options));
+    jinja_dest.params = Box::into_raw(Box::new(params));
+    jinja_dest.rows_processed = 0;
+    jinja_dest.preset = preset;
+    jinja_dest.limits = limits;
+    jinja_dest.bytes_written = 0;
+    jinja_dest.timings = None;
+    jinja_dest.source_relation = pg_sys::Oid::INVALID;
+
+    let jinja_dest = jinja_dest.into_pg();
+
+    // The receiver itself lives in the parent context, which outlives
+    // `memory_context`, so it is still valid when the callback fires.
+    unsafe {
+        (*jinja_dest).reset_callback.func = Some(jinja_reset_callback);
+        (*jinja_dest).reset_callback.arg = jinja_dest as *mut c_void;
+        MemoryContextRegisterResetCallback(memory_context, &mut (*jinja_dest).reset_callback);
+    }
 
-    jinja_dest.into_pg()
+    jinja_dest
 }
@@ -0,0 +1,133 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use minijinja::value::Rest;
+use minijinja::{Environment, Value};
+use pgrx::pg_sys::{MemoryContext, Oid};
+
+use super::sql_function::SqlFunction;
+
+thread_local! {
+    /// Template globals set with `pigiaminja.set_global`, kept for the rest
+    /// of the session. They are not transactional: a rollback keeps them.
+    static SESSION_GLOBALS: RefCell<BTreeMap<String, Value>> = const { RefCell::new(BTreeMap::new()) };
+
+    /// SQL functions registered as template filters and tests for the rest
+    /// of the session, by name
+    static SESSION_FILTERS: RefCell<BTreeMap<String, Oid>> = const { RefCell::new(BTreeMap::new()) };
+    static SESSION_TESTS: RefCell<BTreeMap<String, Oid>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+/// Add the session's globals to a template environment. Called before the
+/// per-statement globals are added, so `params`, `columns` and the like win
+/// over a session global of the same name.
+pub(crate) fn add_session_globals(env: &mut Environment<'static>) {
+    SESSION_GLOBALS.with_borrow(|globals| {
+        for (name, value) in globals {
+            env.add_global(name.clone(), value.clone());
+        }
+    });
+}
+
+/// Add the session's SQL filters and tests to a template environment,
+/// resolving each function once for the whole COPY. Functions dropped since
+/// they were registered are left out.
+///
+/// # Safety
+/// Must run inside a Postgres backend (performs catalog lookups).
+pub(crate) unsafe fn add_session_functions(
+    env: &mut Environment<'static>,
+    memory_context: MemoryContext,
+) {
+    SESSION_FILTERS.with_borrow(|filters| {
+        for (name, function_oid) in filters {
+            let Some(function) = SqlFunction::new(*function_oid, memory_context) else {
+                continue;
+            };
+            let function = Arc::new(function);
+            env.add_filter(name.clone(), move |args: Rest<Value>| function.call(args));
+        }
+    });
+
+    SESSION_TESTS.with_borrow(|tests| {
+        for (name, function_oid) in tests {
+            let Some(function) = SqlFunction::new(*function_oid, memory_context) else {
+                continue;
+            };
+            let function = Arc::new(function);
+            env.add_test(name.clone(), move |args: Rest<Value>| {
+                function.call(args).map(|result| result.is_true())
+            });
+        }
+    });
+}
+
+#[pgrx::pg_schema]
+mod pigiaminja {
+    use minijinja::Value;
+    use pgrx::pg_sys::{errcodes::PgSqlErrorCode, get_func_retset, get_func_signature, Oid};
+    use pgrx::prelude::*;
+    use pgrx::JsonB;
+
+    use super::{SESSION_FILTERS, SESSION_GLOBALS, SESSION_TESTS};
+
+    /// Define a template global for every jinja COPY in this session
+    #[pg_extern(parallel_unsafe)]
+    fn set_global(name: &str, value: JsonB) {
+        SESSION_GLOBALS.with_borrow_mut(|globals| {
+            globals.insert(name.to_string(), Value::from_serialize(value.0));
+        });
+    }
+
+    /// Forget every global defined with set_global
+    #[pg_extern(parallel_unsafe)]
+    fn clear_globals() {
+        SESSION_GLOBALS.with_borrow_mut(|globals| globals.clear());
+    }
+
+    /// Make a SQL function available as a template filter for every jinja
+    /// COPY in this session: `{{ value|name(arg, ...) }}` calls it with the
+    /// value as its first argument
+    #[pg_extern(parallel_unsafe)]
+    fn register_filter(name: &str, function: Oid) {
+        check_template_function(function);
+        SESSION_FILTERS.with_borrow_mut(|filters| {
+            filters.insert(name.to_string(), function);
+        });
+    }
+
+    /// Make a SQL function available as a template test, `{% if value is
+    /// name %}`, true when the function returns a true value
+    #[pg_extern(parallel_unsafe)]
+    fn register_test(name: &str, function: Oid) {
+        check_template_function(function);
+        SESSION_TESTS.with_borrow_mut(|tests| {
+            tests.insert(name.to_string(), function);
+        });
+    }
+
+    /// A filter or test receives the value it is applied to, so the function
+    /// needs at least one argument, and must return a single value
+    fn check_template_function(function: Oid) {
+        let mut arg_types = std::ptr::null_mut();
+        let mut nargs = 0;
+        unsafe { get_func_signature(function, &mut arg_types, &mut nargs) };
+
+        if nargs == 0 {
+            ereport!(
+                ERROR,
+                PgSqlErrorCode::ERRCODE_INVALID_FUNCTION_DEFINITION,
+                "a template filter or test function must take at least one argument"
+            );
+        }
+
+        if unsafe { get_func_retset(function) } {
+            ereport!(
+                ERROR,
+                PgSqlErrorCode::ERRCODE_INVALID_FUNCTION_DEFINITION,
+                "a template filter or test function cannot return a set"
+            );
+        }
+    }
+}
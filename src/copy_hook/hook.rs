@@ -23,14 +23,15 @@ pub extern "C-unwind" fn init_jinja_copy_hook() {
     }
 }
 
-fn process_copy_to_jinja(
+fn process_copy_jinja(
     p_stmt: &PgBox<PlannedStmt>,
     query_string: &CStr,
     read_only_tree: bool,
     dest: *mut DestReceiver,
     query_completion: *mut QueryCompletion,
 ) -> bool {
-    // Import the functions from copy_to module
+    // Import the functions from copy_to and copy_from modules
+    use crate::copy_hook::copy_from::{execute_copy_from_jinja, is_copy_from_jinja_stmt};
     use crate::copy_hook::copy_to::{execute_copy_to_jinja, is_copy_to_jinja_stmt};
 
     // Check if this is a COPY TO statement with jinja format
@@ -39,6 +40,12 @@ fn process_copy_to_jinja(
         return true;
     }
 
+    // Check if this is a COPY FROM statement with jinja format
+    if is_copy_from_jinja_stmt(p_stmt) {
+        execute_copy_from_jinja(p_stmt, query_string, query_completion);
+        return true;
+    }
+
     false
 }
 
@@ -71,7 +78,7 @@ extern "C-unwind" fn jinja_copy_hook(
     let p_stmt = unsafe { PgBox::from_pg(p_stmt) };
     let query_string = unsafe { CStr::from_ptr(query_string) };
 
-    let handled = process_copy_to_jinja(
+    let handled = process_copy_jinja(
         &p_stmt,
         query_string,
         read_only_tree,
@@ -0,0 +1,195 @@
+use std::ffi::{c_char, c_int, CStr};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use pgrx::pg_sys::errcodes::PgSqlErrorCode;
+use pgrx::pg_sys::{
+    ereport, makeStringInfo, pq_beginmessage, pq_copymsgbytes, pq_endmessage, pq_getmsgstring,
+    pq_sendbyte, pq_sendint16, QueryCancelHoldoffCount, StringInfo,
+};
+
+use super::output::check_server_access_privilege;
+use super::program::ProgramPipe;
+
+// Backend libpq entry points that pgrx does not bind, from pq_shim.c. None
+// of them raise an ERROR: connection problems are logged as COMMERROR and
+// reported as EOF.
+unsafe extern "C-unwind" {
+    fn pigiaminja_pq_flush() -> c_int;
+    fn pigiaminja_pq_startmsgread();
+    fn pigiaminja_pq_getbyte() -> c_int;
+    fn pigiaminja_pq_getmessage(s: StringInfo, maxlen: c_int) -> c_int;
+    static pigiaminja_pq_large_message_limit: c_int;
+    static pigiaminja_pq_small_message_limit: c_int;
+}
+
+/// Represents the source for COPY FROM input
+pub enum CopySource {
+    Stdin(FrontendReader),
+    File(File),
+    Program(ProgramPipe),
+}
+
+impl CopySource {
+    /// Create a CopySource from COPY statement parameters. For STDIN this
+    /// starts the copy-in sub-protocol, so it must be the last step that
+    /// can fail before the data is read.
+    pub fn from_copy_stmt(filename: *mut c_char, is_program: bool) -> Result<Self, String> {
+        unsafe {
+            if is_program {
+                check_server_access_privilege(true, true);
+
+                let pipe = ProgramPipe::open_for_read(CStr::from_ptr(filename))?;
+                return Ok(CopySource::Program(pipe));
+            }
+
+            if filename.is_null() {
+                return Ok(CopySource::Stdin(FrontendReader::begin()));
+            }
+
+            check_server_access_privilege(false, true);
+
+            let filename_str = CStr::from_ptr(filename)
+                .to_str()
+                .map_err(|e| format!("Invalid filename: {}", e))?;
+
+            let path = Path::new(filename_str);
+            let file = File::open(path)
+                .map_err(|e| format!("Failed to open file '{}': {}", filename_str, e))?;
+
+            Ok(CopySource::File(file))
+        }
+    }
+
+    /// Finish reading: for a program this waits for it and reports a
+    /// non-zero exit status.
+    pub fn finalize(&mut self) -> Result<(), String> {
+        match self {
+            CopySource::Stdin(_) | CopySource::File(_) => Ok(()),
+            CopySource::Program(program) => program.close(),
+        }
+    }
+}
+
+impl Read for CopySource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            CopySource::Stdin(reader) => Ok(reader.read(buf)),
+            CopySource::File(file) => file.read(buf),
+            CopySource::Program(program) => program.read(buf).map_err(io::Error::other),
+        }
+    }
+}
+
+/// Reads COPY data sent by the client, following the COPY_FRONTEND path of
+/// copyfromparse.c: CopyData messages carry the data, CopyDone ends it and
+/// CopyFail aborts the COPY.
+pub struct FrontendReader {
+    msgbuf: StringInfo,
+    reached_eof: bool,
+}
+
+impl FrontendReader {
+    /// Send CopyInResponse (text format, one column) and flush it so the
+    /// client knows it may start sending.
+    fn begin() -> Self {
+        unsafe {
+            let buf = makeStringInfo();
+            pq_beginmessage(buf, b'G' as _);
+            pq_sendbyte(buf, 0); /* overall format */
+            pq_sendint16(buf, 1);
+            pq_sendint16(buf, 0);
+            pq_endmessage(buf);
+
+            pigiaminja_pq_flush();
+
+            FrontendReader {
+                msgbuf: makeStringInfo(),
+                reached_eof: false,
+            }
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        if buf.is_empty() {
+            return 0;
+        }
+
+        unsafe {
+            while (*self.msgbuf).cursor >= (*self.msgbuf).len {
+                if self.reached_eof || !self.receive_message() {
+                    return 0;
+                }
+            }
+
+            let available = ((*self.msgbuf).len - (*self.msgbuf).cursor) as usize;
+            let count = available.min(buf.len());
+            pq_copymsgbytes(self.msgbuf, buf.as_mut_ptr() as _, count as _);
+            count
+        }
+    }
+
+    /// Receive the next CopyData message into msgbuf. Returns false once the
+    /// client has sent CopyDone.
+    unsafe fn receive_message(&mut self) -> bool {
+        loop {
+            // HOLD_CANCEL_INTERRUPTS: a cancel mid-message would lose sync
+            QueryCancelHoldoffCount += 1;
+            pigiaminja_pq_startmsgread();
+
+            let msg_type = pigiaminja_pq_getbyte();
+            if msg_type == libc::EOF {
+                connection_lost();
+            }
+
+            let max_len = match msg_type as u8 {
+                b'd' => pigiaminja_pq_large_message_limit,
+                b'c' | b'f' | b'H' | b'S' => pigiaminja_pq_small_message_limit,
+                _ => unexpected_message(msg_type),
+            };
+
+            if pigiaminja_pq_getmessage(self.msgbuf, max_len) != 0 {
+                connection_lost();
+            }
+            QueryCancelHoldoffCount -= 1;
+
+            match msg_type as u8 {
+                b'd' => return true,
+                b'c' => {
+                    self.reached_eof = true;
+                    return false;
+                }
+                b'f' => {
+                    let reason = CStr::from_ptr(pq_getmsgstring(self.msgbuf)).to_string_lossy();
+                    ereport!(
+                        ERROR,
+                        PgSqlErrorCode::ERRCODE_QUERY_CANCELED,
+                        format!("COPY from stdin failed: {}", reason)
+                    );
+                }
+                // Flush and Sync are ignored during COPY
+                _ => continue,
+            }
+        }
+    }
+}
+
+fn unexpected_message(msg_type: c_int) -> ! {
+    ereport!(
+        ERROR,
+        PgSqlErrorCode::ERRCODE_PROTOCOL_VIOLATION,
+        format!(
+            "unexpected message type 0x{:02X} during COPY from stdin",
+            msg_type
+        )
+    );
+}
+
+fn connection_lost() -> ! {
+    ereport!(
+        ERROR,
+        PgSqlErrorCode::ERRCODE_CONNECTION_FAILURE,
+        "unexpected EOF on client connection with an open transaction"
+    );
+}
@@ -1,5 +1,18 @@
+pub mod binary_output;
+pub mod copy_from;
 pub mod copy_to;
 pub mod dest_receiver;
+pub mod globals;
 pub mod hook;
+pub mod input;
+pub mod number_format;
 pub mod output;
+pub mod pattern;
 pub mod pg_compat;
+pub mod preset;
+pub mod program;
+pub mod query_function;
+pub mod record;
+pub mod render_pool;
+pub mod sql_function;
+pub mod translations;
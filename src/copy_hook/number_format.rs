@@ -0,0 +1,403 @@
+use std::collections::HashMap;
+use std::ffi::{c_char, CStr, CString};
+use std::sync::{Arc, Mutex};
+
+use minijinja::value::ValueKind;
+use minijinja::{Environment, Error, ErrorKind, Value};
+
+use super::render_pool::require_backend;
+
+// From utils/pg_locale.h, which pgrx does not bind: the C library's lconv
+// for the current lc_numeric and lc_monetary, converted to the database
+// encoding and cached by the backend.
+unsafe extern "C-unwind" {
+    fn PGLC_localeconv() -> *mut libc::lconv;
+}
+
+/// lconv uses CHAR_MAX for "not available in this locale"
+const CHAR_MAX: c_char = c_char::MAX;
+
+/// What the number filters need from a locale, copied out of its lconv
+struct NumberLocale {
+    decimal_point: String,
+    thousands_sep: String,
+    grouping: Vec<u8>,
+    mon_decimal_point: String,
+    mon_thousands_sep: String,
+    mon_grouping: Vec<u8>,
+    currency_symbol: String,
+    int_curr_symbol: String,
+    frac_digits: Option<usize>,
+    p_cs_precedes: bool,
+    p_sep_by_space: bool,
+    n_cs_precedes: bool,
+    n_sep_by_space: bool,
+}
+
+impl NumberLocale {
+    /// The server's lc_numeric / lc_monetary, or the named locale
+    ///
+    /// # Safety
+    /// Must run inside a Postgres backend, on its main thread.
+    unsafe fn load(name: Option<&str>) -> Result<Self, Error> {
+        let Some(name) = name else {
+            let conv = pgrx::pg_sys::ffi::pg_guard_ffi_boundary(|| PGLC_localeconv());
+            return Ok(NumberLocale::from_lconv(&*conv));
+        };
+
+        let unknown_locale = || {
+            Error::new(
+                ErrorKind::InvalidOperation,
+                format!("unknown locale \"{}\"", name),
+            )
+        };
+
+        let c_name = CString::new(name).map_err(|_| unknown_locale())?;
+        let locale = libc::newlocale(
+            libc::LC_NUMERIC_MASK | libc::LC_MONETARY_MASK,
+            c_name.as_ptr(),
+            std::ptr::null_mut(),
+        );
+        if locale.is_null() {
+            return Err(unknown_locale());
+        }
+
+        // localeconv() reports the thread's locale, so switch to it briefly
+        let previous = libc::uselocale(locale);
+        let number_locale = NumberLocale::from_lconv(&*libc::localeconv());
+        libc::uselocale(previous);
+        libc::freelocale(locale);
+
+        Ok(number_locale)
+    }
+
+    unsafe fn from_lconv(conv: &libc::lconv) -> Self {
+        let string = |ptr: *const c_char| {
+            if ptr.is_null() {
+                String::new()
+            } else {
+                CStr::from_ptr(ptr).to_string_lossy().into_owned()
+            }
+        };
+        let grouping = |ptr: *const c_char| {
+            if ptr.is_null() {
+                Vec::new()
+            } else {
+                CStr::from_ptr(ptr).to_bytes().to_vec()
+            }
+        };
+
+        NumberLocale {
+            decimal_point: string(conv.decimal_point),
+            thousands_sep: string(conv.thousands_sep),
+            grouping: grouping(conv.grouping),
+            mon_decimal_point: string(conv.mon_decimal_point),
+            mon_thousands_sep: string(conv.mon_thousands_sep),
+            mon_grouping: grouping(conv.mon_grouping),
+            currency_symbol: string(conv.currency_symbol),
+            int_curr_symbol: string(conv.int_curr_symbol),
+            frac_digits: (conv.frac_digits != CHAR_MAX).then_some(conv.frac_digits as usize),
+            p_cs_precedes: conv.p_cs_precedes == 1,
+            p_sep_by_space: conv.p_sep_by_space == 1,
+            n_cs_precedes: conv.n_cs_precedes == 1,
+            n_sep_by_space: conv.n_sep_by_space == 1,
+        }
+    }
+
+    fn decimal_point(&self) -> &str {
+        if self.decimal_point.is_empty() {
+            "."
+        } else {
+            &self.decimal_point
+        }
+    }
+
+    fn mon_decimal_point(&self) -> &str {
+        if self.mon_decimal_point.is_empty() {
+            self.decimal_point()
+        } else {
+            &self.mon_decimal_point
+        }
+    }
+}
+
+/// Locales loaded by the filters during one COPY. `None` is the server's
+/// lc_numeric / lc_monetary, which cannot change while the COPY runs.
+#[derive(Default)]
+struct Locales(Mutex<HashMap<Option<String>, Arc<NumberLocale>>>);
+
+impl Locales {
+    fn get(&self, name: Option<String>) -> Result<Arc<NumberLocale>, Error> {
+        let mut locales = self.0.lock().expect("locale cache poisoned");
+        if let Some(locale) = locales.get(&name) {
+            return Ok(locale.clone());
+        }
+
+        // Named locales come from the C library, the server's from Postgres
+        if name.is_none() {
+            require_backend("the server's locale")?;
+        }
+        let locale = Arc::new(unsafe { NumberLocale::load(name.as_deref()) }?);
+        locales.insert(name, locale.clone());
+        Ok(locale)
+    }
+}
+
+/// Add the `number_format`, `currency` and `percent` filters. They accept
+/// the values `convert_datum` produces for numbers: integers, floats, and the
+/// text of a numeric, which is formatted without going through a float. NULL
+/// stays NULL.
+pub(crate) fn add_number_filters(env: &mut Environment<'static>) {
+    let locales = Arc::new(Locales::default());
+
+    let number_locales = locales.clone();
+    env.add_filter(
+        "number_format",
+        move |value: Value, decimals: Option<usize>, locale: Option<String>| {
+            if value.is_none() {
+                return Ok(value);
+            }
+            let locale = number_locales.get(locale)?;
+            let mut number = Decimal::from_value(&value, "number_format")?;
+            number.round(decimals.unwrap_or(number.fraction.len()));
+            Ok(Value::from(number.format(
+                locale.decimal_point(),
+                &locale.thousands_sep,
+                &locale.grouping,
+            )))
+        },
+    );
+
+    let currency_locales = locales.clone();
+    env.add_filter(
+        "currency",
+        move |value: Value, code: Option<String>, locale: Option<String>| {
+            if value.is_none() {
+                return Ok(value);
+            }
+            let locale = currency_locales.get(locale)?;
+            let mut number = Decimal::from_value(&value, "currency")?;
+            number.round(locale.frac_digits.unwrap_or(2));
+
+            // The locale's own symbol for its own currency, the code for any
+            // other
+            let symbol = match code {
+                Some(code) if code != locale.int_curr_symbol.trim() => code,
+                _ => locale.currency_symbol.clone(),
+            };
+            let amount = number.format_abs(
+                locale.mon_decimal_point(),
+                &locale.mon_thousands_sep,
+                &locale.mon_grouping,
+            );
+
+            let (precedes, separated) = if number.negative {
+                (locale.n_cs_precedes, locale.n_sep_by_space)
+            } else {
+                (locale.p_cs_precedes, locale.p_sep_by_space)
+            };
+            let space = if separated && !symbol.is_empty() {
+                " "
+            } else {
+                ""
+            };
+            let sign = if number.negative { "-" } else { "" };
+
+            Ok(Value::from(if precedes {
+                format!("{sign}{symbol}{space}{amount}")
+            } else {
+                format!("{sign}{amount}{space}{symbol}")
+            }))
+        },
+    );
+
+    env.add_filter(
+        "percent",
+        move |value: Value, decimals: Option<usize>, locale: Option<String>| {
+            if value.is_none() {
+                return Ok(value);
+            }
+            let locale = locales.get(locale)?;
+            let mut number = Decimal::from_value(&value, "percent")?;
+            number.scale_by_100();
+            number.round(decimals.unwrap_or(0));
+            Ok(Value::from(format!(
+                "{}%",
+                number.format(
+                    locale.decimal_point(),
+                    &locale.thousands_sep,
+                    &locale.grouping
+                )
+            )))
+        },
+    );
+}
+
+/// A number as decimal digits, so numeric values keep their precision
+struct Decimal {
+    negative: bool,
+    integer: Vec<u8>,
+    fraction: Vec<u8>,
+}
+
+impl Decimal {
+    fn from_value(value: &Value, filter: &str) -> Result<Self, Error> {
+        let not_a_number = || {
+            Error::new(
+                ErrorKind::InvalidOperation,
+                format!("{} expects a number, got {}", filter, value.kind()),
+            )
+        };
+
+        let text = match value.kind() {
+            ValueKind::Number => match value.as_i64() {
+                Some(number) => number.to_string(),
+                None => f64::try_from(value.clone())
+                    .map_err(|_| not_a_number())?
+                    .to_string(),
+            },
+            ValueKind::String => value.as_str().unwrap_or_default().trim().to_string(),
+            _ => return Err(not_a_number()),
+        };
+
+        Decimal::parse(&text)
+            .or_else(|| {
+                // Exponent notation, as float8's output uses for large values
+                let number = text.parse::<f64>().ok().filter(|n| n.is_finite())?;
+                Decimal::parse(&number.to_string())
+            })
+            .ok_or_else(not_a_number)
+    }
+
+    fn parse(text: &str) -> Option<Self> {
+        let (negative, unsigned) = match text.as_bytes().first()? {
+            b'-' => (true, &text[1..]),
+            b'+' => (false, &text[1..]),
+            _ => (false, text),
+        };
+        let (integer, fraction) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+
+        let is_digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+        if (integer.is_empty() && fraction.is_empty())
+            || !is_digits(integer)
+            || !is_digits(fraction)
+        {
+            return None;
+        }
+
+        let mut number = Decimal {
+            negative,
+            integer: integer.trim_start_matches('0').as_bytes().to_vec(),
+            fraction: fraction.as_bytes().to_vec(),
+        };
+        if number.integer.is_empty() {
+            number.integer.push(b'0');
+        }
+        Some(number)
+    }
+
+    fn scale_by_100(&mut self) {
+        for _ in 0..2 {
+            let digit = if self.fraction.is_empty() {
+                b'0'
+            } else {
+                self.fraction.remove(0)
+            };
+            if self.integer == b"0" {
+                self.integer.clear();
+            }
+            self.integer.push(digit);
+        }
+        if self.integer.is_empty() {
+            self.integer.push(b'0');
+        }
+    }
+
+    /// Round half away from zero to `decimals` fraction digits, padding with
+    /// zeros when there are fewer
+    fn round(&mut self, decimals: usize) {
+        if self.fraction.len() > decimals {
+            let round_up = self.fraction[decimals] >= b'5';
+            self.fraction.truncate(decimals);
+
+            if round_up {
+                let mut carry = true;
+                for digit in self
+                    .fraction
+                    .iter_mut()
+                    .rev()
+                    .chain(self.integer.iter_mut().rev())
+                {
+                    if *digit == b'9' {
+                        *digit = b'0';
+                    } else {
+                        *digit += 1;
+                        carry = false;
+                        break;
+                    }
+                }
+                if carry {
+                    self.integer.insert(0, b'1');
+                }
+            }
+        }
+        self.fraction.resize(decimals, b'0');
+
+        // No "-0.00"
+        if self
+            .integer
+            .iter()
+            .chain(&self.fraction)
+            .all(|d| *d == b'0')
+        {
+            self.negative = false;
+        }
+    }
+
+    fn format(&self, decimal_point: &str, thousands_sep: &str, grouping: &[u8]) -> String {
+        let sign = if self.negative { "-" } else { "" };
+        format!(
+            "{}{}",
+            sign,
+            self.format_abs(decimal_point, thousands_sep, grouping)
+        )
+    }
+
+    fn format_abs(&self, decimal_point: &str, thousands_sep: &str, grouping: &[u8]) -> String {
+        let mut text = group_digits(&self.integer, thousands_sep, grouping);
+        if !self.fraction.is_empty() {
+            text.push_str(decimal_point);
+            text.push_str(std::str::from_utf8(&self.fraction).expect("digits are ASCII"));
+        }
+        text
+    }
+}
+
+/// Insert `separator` between digit groups as lconv's grouping describes
+/// them: sizes from the right, the last size repeating, CHAR_MAX ending the
+/// grouping
+fn group_digits(digits: &[u8], separator: &str, grouping: &[u8]) -> String {
+    let digits = std::str::from_utf8(digits).expect("digits are ASCII");
+    if separator.is_empty() || grouping.is_empty() {
+        return digits.to_string();
+    }
+
+    let mut groups = Vec::new();
+    let mut end = digits.len();
+    let mut sizes = grouping.iter();
+    let mut size = 0;
+    while end > 0 {
+        if let Some(next) = sizes.next() {
+            size = *next as usize;
+        }
+        if size == 0 || size >= CHAR_MAX as usize || size >= end {
+            groups.push(&digits[..end]);
+            break;
+        }
+        groups.push(&digits[end - size..end]);
+        end -= size;
+    }
+
+    groups.reverse();
+    groups.join(separator)
+}
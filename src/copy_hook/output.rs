@@ -2,24 +2,33 @@ use std::ffi::{c_char, CStr};
 use std::fs::File;
 use std::io::{BufWriter, Write};
 use std::path::Path;
-use std::process::{Child, Command, Stdio};
 
 use pgrx::pg_sys::errcodes::PgSqlErrorCode;
 use pgrx::pg_sys::{
     ereport, has_privs_of_role, makeStringInfo, pq_beginmessage, pq_endmessage, pq_sendbytes,
-    GetUserId, Oid, ROLE_PG_EXECUTE_SERVER_PROGRAM, ROLE_PG_WRITE_SERVER_FILES,
+    GetUserId, Oid, PROGRESS_COPY_TYPE_FILE, PROGRESS_COPY_TYPE_PIPE, PROGRESS_COPY_TYPE_PROGRAM,
+    ROLE_PG_EXECUTE_SERVER_PROGRAM, ROLE_PG_READ_SERVER_FILES, ROLE_PG_WRITE_SERVER_FILES,
 };
 
-/// Same permission checks DoCopy applies: writing a server-side file or
-/// piping through a program is reserved to superusers and the built-in
-/// pg_write_server_files / pg_execute_server_program roles.
-fn check_destination_privilege(is_program: bool) {
+use super::program::ProgramPipe;
+
+/// Same permission checks DoCopy applies: reading or writing a server-side
+/// file or piping through a program is reserved to superusers and the
+/// built-in pg_read_server_files / pg_write_server_files /
+/// pg_execute_server_program roles.
+pub(super) fn check_server_access_privilege(is_program: bool, is_from: bool) {
     let (role_oid, role_name, action) = if is_program {
         (
             ROLE_PG_EXECUTE_SERVER_PROGRAM,
             "pg_execute_server_program",
             "COPY to or from an external program",
         )
+    } else if is_from {
+        (
+            ROLE_PG_READ_SERVER_FILES,
+            "pg_read_server_files",
+            "COPY from a file",
+        )
     } else {
         (
             ROLE_PG_WRITE_SERVER_FILES,
@@ -46,7 +55,9 @@ fn check_destination_privilege(is_program: bool) {
 pub enum CopyDestination {
     Stdout,
     File(BufWriter<File>),
-    Program(Child),  // Hold Child to access stdin and wait on drop
+    Program(ProgramPipe),
+    /// DRY_RUN: rows are rendered and thrown away
+    Discard,
 }
 
 impl CopyDestination {
@@ -55,20 +66,10 @@ impl CopyDestination {
         unsafe {
             // Check for COPY TO PROGRAM
             if is_program {
-                check_destination_privilege(true);
-
-                let command_str = CStr::from_ptr(filename)
-                    .to_str()
-                    .map_err(|e| format!("Invalid command: {}", e))?;
-
-                let child = Command::new("sh")
-                    .arg("-c")
-                    .arg(command_str)
-                    .stdin(Stdio::piped())
-                    .spawn()
-                    .map_err(|e| format!("Failed to spawn program '{}': {}", command_str, e))?;
+                check_server_access_privilege(true, false);
 
-                return Ok(CopyDestination::Program(child));
+                let pipe = ProgramPipe::open_for_write(CStr::from_ptr(filename))?;
+                return Ok(CopyDestination::Program(pipe));
             }
 
             // Check if filename is null -> STDOUT
@@ -77,7 +78,7 @@ impl CopyDestination {
             }
 
             // Otherwise, it's a file path
-            check_destination_privilege(false);
+            check_server_access_privilege(false, false);
 
             let filename_str = CStr::from_ptr(filename)
                 .to_str()
@@ -109,15 +110,8 @@ impl CopyDestination {
                     .write_all(data)
                     .map_err(|e| format!("Failed to write to file: {}", e))
             }
-            CopyDestination::Program(child) => {
-                if let Some(stdin) = child.stdin.as_mut() {
-                    stdin
-                        .write_all(data)
-                        .map_err(|e| format!("Failed to write to program: {}", e))
-                } else {
-                    Err("Program stdin not available".to_string())
-                }
-            }
+            CopyDestination::Program(program) => program.write_all(data),
+            CopyDestination::Discard => Ok(()),
         }
     }
 
@@ -126,22 +120,40 @@ impl CopyDestination {
         matches!(self, CopyDestination::Stdout)
     }
 
+    /// How pg_stat_progress_copy shows the destination: STDOUT is a PIPE, as
+    /// for native COPY. None for a dry run, which has no destination.
+    pub fn progress_type(&self) -> Option<u32> {
+        match self {
+            CopyDestination::Stdout => Some(PROGRESS_COPY_TYPE_PIPE),
+            CopyDestination::File(_) => Some(PROGRESS_COPY_TYPE_FILE),
+            CopyDestination::Program(_) => Some(PROGRESS_COPY_TYPE_PROGRAM),
+            CopyDestination::Discard => None,
+        }
+    }
+
+    /// Check if rendered rows are thrown away (DRY_RUN)
+    pub fn is_discard(&self) -> bool {
+        matches!(self, CopyDestination::Discard)
+    }
+
     /// Finalize the destination (flush buffers, etc.)
     pub fn finalize(&mut self) -> Result<(), String> {
         match self {
-            CopyDestination::Stdout => Ok(()),
+            CopyDestination::Stdout | CopyDestination::Discard => Ok(()),
             CopyDestination::File(writer) => writer
                 .flush()
                 .map_err(|e| format!("Failed to flush file: {}", e)),
-            CopyDestination::Program(child) => {
-                // Drop stdin to signal EOF
-                drop(child.stdin.take());
-                // Wait for child to exit
-                child
-                    .wait()
-                    .map_err(|e| format!("Failed to wait for program: {}", e))?;
-                Ok(())
-            }
+            CopyDestination::Program(program) => program.close(),
+        }
+    }
+
+    /// Release the destination after the COPY was aborted by an ERROR. Runs
+    /// during transaction abort, so it must not raise; a program pipe is left
+    /// for fd.c to close.
+    pub fn abort(&mut self) {
+        match self {
+            CopyDestination::Stdout | CopyDestination::File(_) | CopyDestination::Discard => {}
+            CopyDestination::Program(program) => program.forget(),
         }
     }
 }
@@ -0,0 +1,104 @@
+use std::sync::OnceLock;
+
+use regex::bytes::Regex;
+
+/// Turns an input line into column values for COPY FROM with FORMAT 'jinja'.
+///
+/// Built either from a PATTERN option (a regular expression whose named
+/// groups are the column names) or from a TEMPLATE option, where every
+/// `{{ column }}` / `{{ row.column }}` placeholder becomes a capture group
+/// and the text around it must match literally.
+pub(crate) struct ImportPattern {
+    regex: Regex,
+    columns: Vec<(String, usize)>,
+}
+
+impl ImportPattern {
+    pub fn from_regex(pattern: &str) -> Result<Self, String> {
+        let regex = Regex::new(pattern).map_err(|e| format!("invalid import pattern: {}", e))?;
+
+        let columns: Vec<(String, usize)> = regex
+            .capture_names()
+            .enumerate()
+            .filter_map(|(index, name)| name.map(|name| (name.to_string(), index)))
+            .collect();
+
+        if columns.is_empty() {
+            return Err("import pattern must contain at least one named group".to_string());
+        }
+
+        Ok(ImportPattern { regex, columns })
+    }
+
+    pub fn from_template(template: &str) -> Result<Self, String> {
+        if template.contains("{%") || template.contains("{#") {
+            return Err("import templates only support {{ column }} placeholders".to_string());
+        }
+
+        // Placeholders may span lines in a multi-line record
+        let mut pattern = String::from("(?s)^");
+        let mut seen = Vec::new();
+        let mut last_end = 0;
+
+        for placeholder in placeholder_regex().captures_iter(template) {
+            let whole = placeholder.get(0).unwrap();
+            let column = placeholder.get(1).unwrap().as_str();
+
+            if seen.contains(&column) {
+                return Err(format!(
+                    "column \"{}\" appears more than once in the import template",
+                    column
+                ));
+            }
+            seen.push(column);
+
+            pattern.push_str(&literal(&template[last_end..whole.start()])?);
+            pattern.push_str(&format!("(?P<{}>.*?)", column));
+            last_end = whole.end();
+        }
+
+        pattern.push_str(&literal(&template[last_end..])?);
+        pattern.push('$');
+
+        Self::from_regex(&pattern)
+    }
+
+    /// Column names in the order their groups appear in the pattern
+    pub fn column_names(&self) -> impl Iterator<Item = &str> {
+        self.columns.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Capture group index for a column, if the pattern has one
+    pub fn group_index(&self, column: &str) -> Option<usize> {
+        self.columns
+            .iter()
+            .find(|(name, _)| name == column)
+            .map(|(_, index)| *index)
+    }
+
+    pub fn captures<'l>(&self, line: &'l [u8]) -> Option<regex::bytes::Captures<'l>> {
+        self.regex.captures(line)
+    }
+}
+
+/// The text between placeholders, which has to match literally. Any `{{`
+/// left in it is a placeholder the import template does not support, and
+/// must not silently become text every line has to contain.
+fn literal(text: &str) -> Result<String, String> {
+    if text.contains("{{") {
+        return Err(format!(
+            "unsupported placeholder in import template: \"{}\"",
+            text
+        ));
+    }
+    Ok(regex::escape(text))
+}
+
+fn placeholder_regex() -> &'static regex::Regex {
+    static PLACEHOLDER: OnceLock<regex::Regex> = OnceLock::new();
+
+    PLACEHOLDER.get_or_init(|| {
+        regex::Regex::new(r"\{\{-?\s*(?:row\.)?([A-Za-z_][A-Za-z0-9_]*)\s*-?\}\}")
+            .expect("placeholder regex is valid")
+    })
+}
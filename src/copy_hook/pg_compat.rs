@@ -1,6 +1,13 @@
-use std::ffi::c_char;
+use std::ffi::{c_char, c_void};
 
-use pgrx::pg_sys::{List, QueryEnvironment, RawStmt};
+#[cfg(any(feature = "pg16", feature = "pg17", feature = "pg18"))]
+use pgrx::pg_sys::RTEPermissionInfo;
+use pgrx::pg_sys::{
+    aclcheck_error, bms_add_member, get_func_name, AclResult, FirstLowInvalidHeapAttributeNumber,
+    GetUserId, List, Node, ObjectType, Oid, ParseNamespaceItem, ParseState, QueryEnvironment,
+    RawStmt, ACL_EXECUTE, ACL_INSERT,
+};
+use pgrx::PgList;
 
 // PostgreSQL version compatibility function for pg_analyze_and_rewrite
 pub(crate) fn pg_analyze_and_rewrite(
@@ -30,3 +37,87 @@ pub(crate) fn pg_analyze_and_rewrite(
         )
     }
 }
+
+// PostgreSQL version compatibility for the INSERT permission check DoCopy
+// performs before COPY FROM. PG16 moved permissions from the range table
+// entry into RTEPermissionInfo.
+pub(crate) fn check_copy_from_permissions(
+    pstate: *mut ParseState,
+    nsitem: *mut ParseNamespaceItem,
+    attnums: *mut List,
+) {
+    let columns = unsafe { PgList::<c_void>::from_pg(attnums) };
+
+    #[cfg(any(feature = "pg14", feature = "pg15"))]
+    unsafe {
+        let rte = (*nsitem).p_rte;
+        (*rte).requiredPerms = ACL_INSERT as _;
+        for attnum in columns.iter_int() {
+            (*rte).insertedCols = bms_add_member(
+                (*rte).insertedCols,
+                attnum - FirstLowInvalidHeapAttributeNumber,
+            );
+        }
+
+        pgrx::pg_sys::ExecCheckRTPerms((*pstate).p_rtable, true);
+    }
+
+    #[cfg(any(feature = "pg16", feature = "pg17", feature = "pg18"))]
+    unsafe {
+        let perminfo = (*nsitem).p_perminfo;
+        (*perminfo).requiredPerms = ACL_INSERT as _;
+        for attnum in columns.iter_int() {
+            (*perminfo).insertedCols = bms_add_member(
+                (*perminfo).insertedCols,
+                attnum - FirstLowInvalidHeapAttributeNumber,
+            );
+        }
+
+        let mut perminfos = PgList::<RTEPermissionInfo>::new();
+        perminfos.push(perminfo);
+        pgrx::pg_sys::ExecCheckPermissions((*pstate).p_rtable, perminfos.into_pg(), true);
+    }
+}
+
+// PostgreSQL version compatibility for strVal(): PG15 replaced the Value
+// node with a String node
+pub(crate) unsafe fn str_val(node: *mut Node) -> *mut c_char {
+    #[cfg(feature = "pg14")]
+    {
+        (*(node as *mut pgrx::pg_sys::Value)).val.str_
+    }
+
+    #[cfg(any(feature = "pg15", feature = "pg16", feature = "pg17", feature = "pg18"))]
+    {
+        (*(node as *mut pgrx::pg_sys::String)).sval
+    }
+}
+
+// PostgreSQL version compatibility for the EXECUTE check on a SQL function
+// registered as a template filter. PG16 replaced the per-object aclcheck
+// functions with object_aclcheck.
+pub(crate) fn check_function_execute_permission(function_oid: Oid) {
+    #[cfg(any(feature = "pg14", feature = "pg15"))]
+    let result =
+        unsafe { pgrx::pg_sys::pg_proc_aclcheck(function_oid, GetUserId(), ACL_EXECUTE as _) };
+
+    #[cfg(any(feature = "pg16", feature = "pg17", feature = "pg18"))]
+    let result = unsafe {
+        pgrx::pg_sys::object_aclcheck(
+            pgrx::pg_sys::ProcedureRelationId,
+            function_oid,
+            GetUserId(),
+            ACL_EXECUTE as _,
+        )
+    };
+
+    if result != AclResult::ACLCHECK_OK {
+        unsafe {
+            aclcheck_error(
+                result,
+                ObjectType::OBJECT_FUNCTION,
+                get_func_name(function_oid),
+            )
+        };
+    }
+}
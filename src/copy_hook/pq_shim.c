@@ -0,0 +1,36 @@
+/*
+ * Backend libpq entry points for the COPY FROM STDIN reader (input.rs) that
+ * pgrx does not bind. Compiled against the server headers, so pq_flush(), a
+ * macro over PqCommMethods, and the message size limits come from the real
+ * definitions rather than a copy of them.
+ */
+#include "postgres.h"
+
+#include "libpq/libpq.h"
+
+const int	pigiaminja_pq_large_message_limit = PQ_LARGE_MESSAGE_LIMIT;
+const int	pigiaminja_pq_small_message_limit = PQ_SMALL_MESSAGE_LIMIT;
+
+int
+pigiaminja_pq_flush(void)
+{
+	return pq_flush();
+}
+
+void
+pigiaminja_pq_startmsgread(void)
+{
+	pq_startmsgread();
+}
+
+int
+pigiaminja_pq_getbyte(void)
+{
+	return pq_getbyte();
+}
+
+int
+pigiaminja_pq_getmessage(StringInfo s, int maxlen)
+{
+	return pq_getmessage(s, maxlen);
+}
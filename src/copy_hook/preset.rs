@@ -0,0 +1,237 @@
+use minijinja::Value;
+use regex::Regex;
+
+/// Block rendered once before the first row
+pub(crate) const HEADER_BLOCK: &str = "header";
+/// Block rendered for every row, with `row` in its context
+pub(crate) const ROW_BLOCK: &str = "row";
+/// Block rendered once after the last row
+pub(crate) const FOOTER_BLOCK: &str = "footer";
+
+/// Built-in templates selected with the PRESET option. The template source is
+/// generated from the result's column names, and its header, row and footer
+/// blocks are rendered separately, so a TEMPLATE that `{% extends %}` the
+/// preset can replace any of them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Preset {
+    Ndjson,
+    Markdown,
+    HtmlTable,
+    Xml,
+}
+
+impl Preset {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "ndjson" => Some(Preset::Ndjson),
+            "markdown" => Some(Preset::Markdown),
+            "html_table" => Some(Preset::HtmlTable),
+            "xml" => Some(Preset::Xml),
+            _ => None,
+        }
+    }
+
+    /// Template name the preset is registered under, for `{% extends %}`
+    pub fn name(self) -> &'static str {
+        match self {
+            Preset::Ndjson => "ndjson",
+            Preset::Markdown => "markdown",
+            Preset::HtmlTable => "html_table",
+            Preset::Xml => "xml",
+        }
+    }
+
+    /// Generate the preset's template for these columns. Everything is
+    /// wrapped in `{% if false %}` so evaluating the template itself renders
+    /// nothing; the blocks are only ever rendered by name.
+    pub fn template_source(self, columns: &[Box<str>]) -> String {
+        let (header, row, footer) = match self {
+            Preset::Ndjson => (String::new(), ndjson_row(columns), String::new()),
+            Preset::Markdown => (markdown_header(columns), markdown_row(columns), String::new()),
+            Preset::HtmlTable => (
+                html_table_header(columns),
+                html_table_row(columns),
+                "</tbody>\n</table>\n".to_string(),
+            ),
+            Preset::Xml => (
+                "<?xml version=\"1.0\"?>\n<rows xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\">\n"
+                    .to_string(),
+                xml_row(columns),
+                "</rows>\n".to_string(),
+            ),
+        };
+
+        format!(
+            "{{% if false %}}\
+             {{% block {HEADER_BLOCK} %}}{header}{{% endblock %}}\
+             {{% block {ROW_BLOCK} %}}{row}{{% endblock %}}\
+             {{% block {FOOTER_BLOCK} %}}{footer}{{% endblock %}}\
+             {{% endif %}}"
+        )
+    }
+}
+
+/// `markdown_cell` filter used by the markdown preset: pipes and line breaks
+/// would end the table cell and `<` / `&` would be read as inline HTML. NULL
+/// is an empty cell.
+pub(crate) fn markdown_cell(value: Value) -> String {
+    if value.is_none() {
+        return String::new();
+    }
+
+    let mut cell = String::new();
+    for c in value.to_string().chars() {
+        match c {
+            '\\' => cell.push_str("\\\\"),
+            '|' => cell.push_str("\\|"),
+            '<' => cell.push_str("&lt;"),
+            '&' => cell.push_str("&amp;"),
+            '\n' => cell.push_str("<br>"),
+            '\r' => {}
+            _ => cell.push(c),
+        }
+    }
+    cell
+}
+
+/// `xml_escape` filter used by the xml preset: only the characters XML
+/// itself needs escaped, unlike `|e`, which escapes for HTML (`'` and `/` too).
+pub(crate) fn xml_escape(value: Value) -> String {
+    let mut escaped = String::new();
+    for c in value.to_string().chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Whether a TEMPLATE given along with PRESET extends a template, as it has
+/// to: the preset's blocks are rendered by name, and a template that does
+/// not extend the preset has none of them.
+pub(crate) fn extends_template(source: &str) -> bool {
+    Regex::new(r"\{%[-+]?\s*extends\s")
+        .expect("valid regex")
+        .is_match(source)
+}
+
+fn ndjson_row(columns: &[Box<str>]) -> String {
+    let fields: Vec<String> = columns
+        .iter()
+        .map(|name| {
+            let name = jinja_string(name);
+            format!("{{{{ {name}|tojson }}}}:{{{{ row[{name}]|tojson }}}}")
+        })
+        .collect();
+
+    // The opening brace is an expression: a literal one would run into the
+    // first `{{`
+    format!("{{{{ \"{{\" }}}}{}}}\n", fields.join(","))
+}
+
+fn markdown_header(columns: &[Box<str>]) -> String {
+    let names: Vec<String> = columns
+        .iter()
+        .map(|name| format!(" {{{{ {}|markdown_cell }}}} ", jinja_string(name)))
+        .collect();
+    let rule = vec![" --- "; columns.len()];
+
+    format!("|{}|\n|{}|\n", names.join("|"), rule.join("|"))
+}
+
+fn markdown_row(columns: &[Box<str>]) -> String {
+    let cells: Vec<String> = columns
+        .iter()
+        .map(|name| format!(" {{{{ row[{}]|markdown_cell }}}} ", jinja_string(name)))
+        .collect();
+
+    format!("|{}|\n", cells.join("|"))
+}
+
+fn html_table_header(columns: &[Box<str>]) -> String {
+    let names: String = columns
+        .iter()
+        .map(|name| format!("<th>{{{{ {}|e }}}}</th>", jinja_string(name)))
+        .collect();
+
+    format!("<table>\n<thead>\n<tr>{names}</tr>\n</thead>\n<tbody>\n")
+}
+
+fn html_table_row(columns: &[Box<str>]) -> String {
+    let cells: String = columns
+        .iter()
+        .map(|name| {
+            let value = format!("row[{}]", jinja_string(name));
+            format!("<td>{{% if {value} is not none %}}{{{{ {value}|e }}}}{{% endif %}}</td>")
+        })
+        .collect();
+
+    format!("<tr>{cells}</tr>\n")
+}
+
+fn xml_row(columns: &[Box<str>]) -> String {
+    let elements: String = columns
+        .iter()
+        .map(|name| {
+            let value = format!("row[{}]", jinja_string(name));
+            let element = xml_name(name);
+            format!(
+                "  {{% if {value} is not none %}}<{element}>{{{{ {value}|xml_escape }}}}</{element}>\
+                 {{% else %}}<{element} xsi:nil=\"true\"/>{{% endif %}}\n"
+            )
+        })
+        .collect();
+
+    format!("<row>\n{elements}</row>\n")
+}
+
+/// Quote a column name as a Jinja string literal
+fn jinja_string(value: &str) -> String {
+    let mut literal = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => literal.push_str("\\\""),
+            '\\' => literal.push_str("\\\\"),
+            '\n' => literal.push_str("\\n"),
+            '\r' => literal.push_str("\\r"),
+            '\t' => literal.push_str("\\t"),
+            _ => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+/// Map a column name to an XML element name the way PostgreSQL's
+/// table_to_xml does: characters that are not allowed in a name, a leading
+/// "xml" and the `_x` escape prefix itself become `_xHHHH_`.
+fn xml_name(name: &str) -> String {
+    let starts_with_xml = name
+        .get(..3)
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case("xml"));
+
+    let mut element = String::new();
+    let mut chars = name.chars().peekable();
+    let mut first = true;
+    while let Some(c) = chars.next() {
+        let valid = if first {
+            c.is_alphabetic() || c == '_'
+        } else {
+            c.is_alphanumeric() || matches!(c, '_' | '-' | '.')
+        };
+        let escape =
+            !valid || (c == '_' && chars.peek() == Some(&'x')) || (first && starts_with_xml);
+
+        if escape {
+            element.push_str(&format!("_x{:04X}_", c as u32));
+        } else {
+            element.push(c);
+        }
+        first = false;
+    }
+    element
+}
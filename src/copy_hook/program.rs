@@ -0,0 +1,185 @@
+use std::ffi::{c_int, CStr, CString};
+use std::fs::File;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+
+use pgrx::pg_sys;
+use pgrx::pg_sys::errcodes::PgSqlErrorCode;
+use pgrx::pg_sys::{
+    ereport, wait_result_to_str, ClosePipeStream, FileClose, FilePathName, OpenPipeStream,
+    OpenTemporaryFile,
+};
+
+/// How much of a failing program's stderr is kept for the error report.
+const STDERR_TAIL_BYTES: u64 = 4096;
+
+/// A COPY TO/FROM PROGRAM pipe, opened through the backend's `OpenPipeStream` like
+/// native COPY does. That gives the child the same signal setup (`SIGPIPE`
+/// restored to default) and file descriptor accounting, and since fd.c tracks
+/// the stream it is closed and the child waited for at (sub)transaction abort,
+/// so the program never outlives the COPY that started it.
+///
+/// The program's stderr goes to a backend temporary file, so that the tail of
+/// it can be reported if the program fails. Like the pipe, the file belongs to
+/// the transaction's resource owner and is removed at abort.
+pub struct ProgramPipe {
+    command: String,
+    file: *mut pg_sys::FILE,
+    stderr_file: pg_sys::File,
+}
+
+impl ProgramPipe {
+    /// Start `command` with its stdin connected to the pipe (COPY TO PROGRAM).
+    pub fn open_for_write(command: &CStr) -> Result<Self, String> {
+        Self::open(command, c"w")
+    }
+
+    /// Start `command` with its stdout connected to the pipe (COPY FROM PROGRAM).
+    pub fn open_for_read(command: &CStr) -> Result<Self, String> {
+        Self::open(command, c"r")
+    }
+
+    fn open(command: &CStr, mode: &CStr) -> Result<Self, String> {
+        let command_str = command
+            .to_str()
+            .map_err(|e| format!("Invalid command: {}", e))?;
+
+        let stderr_file = unsafe { OpenTemporaryFile(false) };
+        let stderr_path = unsafe { CStr::from_ptr(FilePathName(stderr_file)) }
+            .to_string_lossy()
+            .replace('\'', "'\\''");
+        // A group rather than a subshell, so the command runs just as it
+        // would on its own; the newline ends a trailing comment.
+        let wrapped = CString::new(format!("{{ {}\n}} 2>'{}'", command_str, stderr_path))
+            .map_err(|e| format!("Invalid command: {}", e))?;
+
+        let file = unsafe { OpenPipeStream(wrapped.as_ptr(), mode.as_ptr()) };
+        if file.is_null() {
+            let e = std::io::Error::last_os_error();
+            unsafe { FileClose(stderr_file) };
+            return Err(format!(
+                "could not execute command \"{}\": {}",
+                command_str, e
+            ));
+        }
+
+        Ok(ProgramPipe {
+            command: command_str.to_string(),
+            file,
+            stderr_file,
+        })
+    }
+
+    pub fn write_all(&mut self, data: &[u8]) -> Result<(), String> {
+        if self.file.is_null() {
+            return Err("Program stdin not available".to_string());
+        }
+
+        let file = self.file as *mut libc::FILE;
+        let written = unsafe { libc::fwrite(data.as_ptr() as *const _, 1, data.len(), file) };
+        if written != data.len() || unsafe { libc::ferror(file) } != 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == ErrorKind::BrokenPipe {
+                // The program went away. Like native COPY, close the pipe
+                // first: its exit status usually explains more than EPIPE.
+                self.close()?;
+            }
+            return Err(format!("Failed to write to program: {}", e));
+        }
+        Ok(())
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, String> {
+        if self.file.is_null() {
+            return Err("Program stdout not available".to_string());
+        }
+
+        let file = self.file as *mut libc::FILE;
+        let read = unsafe { libc::fread(buf.as_mut_ptr() as *mut _, 1, buf.len(), file) };
+        if read == 0 && unsafe { libc::ferror(file) } != 0 {
+            return Err(format!(
+                "Failed to read from program: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+        Ok(read)
+    }
+
+    /// Close the pipe and wait for the program, raising an ERROR if it exited
+    /// non-zero or was killed by a signal.
+    pub fn close(&mut self) -> Result<(), String> {
+        if self.file.is_null() {
+            return Ok(());
+        }
+
+        let rc = unsafe { ClosePipeStream(self.file) };
+        self.file = std::ptr::null_mut();
+        if rc == -1 {
+            return Err(format!(
+                "Failed to close pipe to program: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+        if rc != 0 {
+            self.report_failure(rc);
+        }
+        self.close_stderr();
+        Ok(())
+    }
+
+    /// Drop our handle without closing it, for use during transaction abort:
+    /// fd.c closes the stream (waiting for the child) as part of the same
+    /// abort, and closing it here too could free the stream twice.
+    pub fn forget(&mut self) {
+        self.file = std::ptr::null_mut();
+        self.stderr_file = 0;
+    }
+
+    /// Close (and so remove) the file holding the program's stderr.
+    fn close_stderr(&mut self) {
+        if self.stderr_file > 0 {
+            unsafe { FileClose(self.stderr_file) };
+            self.stderr_file = 0;
+        }
+    }
+
+    /// The last `STDERR_TAIL_BYTES` the program wrote to stderr.
+    fn stderr_tail(&self) -> String {
+        if self.stderr_file <= 0 {
+            return String::new();
+        }
+
+        let path = unsafe { CStr::from_ptr(FilePathName(self.stderr_file)) }
+            .to_string_lossy()
+            .into_owned();
+        let mut tail = Vec::new();
+        if let Ok(mut file) = File::open(path) {
+            let len = file.metadata().map(|m| m.len()).unwrap_or(0);
+            let start = len.saturating_sub(STDERR_TAIL_BYTES);
+            if file.seek(SeekFrom::Start(start)).is_ok() {
+                let _ = file.read_to_end(&mut tail);
+            }
+        }
+        String::from_utf8_lossy(&tail).trim_end().to_string()
+    }
+
+    /// Raise the same ERROR native COPY does for a failed program, with the
+    /// tail of its stderr appended to the detail.
+    fn report_failure(&mut self, exit_status: c_int) -> ! {
+        let mut detail = unsafe { CStr::from_ptr(wait_result_to_str(exit_status)) }
+            .to_string_lossy()
+            .into_owned();
+        let tail = self.stderr_tail();
+        if !tail.is_empty() {
+            detail.push_str("\nProgram stderr:\n");
+            detail.push_str(&tail);
+        }
+        self.close_stderr();
+
+        ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_EXTERNAL_ROUTINE_EXCEPTION,
+            format!("program \"{}\" failed", self.command),
+            detail
+        );
+    }
+}
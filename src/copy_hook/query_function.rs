@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::sync::{Arc, Mutex};
+
+use minijinja::value::{Rest, ValueKind};
+use minijinja::{Environment, Error, ErrorKind, Value};
+use pgrx::pg_sys::{
+    self, AsPgCStr, CurrentMemoryContext, Datum, SPI_connect, SPI_execute_with_args, SPI_finish,
+    SPI_getbinval, SPI_result_code_string, SPI_tuptable, XactReadOnly, BOOLOID, FLOAT8OID, INT8OID,
+    UNKNOWNOID,
+};
+use pgrx::{GucSetting, IntoDatum};
+
+use super::dest_receiver::{column_conv_for, convert_datum, RowKeys};
+use super::render_pool::require_backend;
+
+/// `pigiaminja.enable_query_function`: make `query()` available to templates.
+pub static ENABLE_QUERY_FUNCTION: GucSetting<bool> = GucSetting::<bool>::new(false);
+
+/// Add `query(sql, *args)`: run a read-only query, with the extra arguments
+/// as `$1`, `$2`, ..., and return its rows as a list of row maps. Results
+/// are cached for the rest of the COPY, by statement and arguments, so a
+/// lookup repeated for every row only runs once per distinct value.
+pub(crate) fn add_query_function(env: &mut Environment<'static>) {
+    let enabled = ENABLE_QUERY_FUNCTION.get();
+    let cache: Mutex<HashMap<(String, Vec<Value>), Value>> = Mutex::new(HashMap::new());
+
+    env.add_function(
+        "query",
+        move |sql: String, args: Rest<Value>| -> Result<Value, Error> {
+            if !enabled {
+                return Err(Error::new(
+                    ErrorKind::InvalidOperation,
+                    "query() is disabled, set pigiaminja.enable_query_function to use it",
+                ));
+            }
+
+            require_backend("query()")?;
+            let key = (sql, args.0);
+            let mut cache = cache.lock().expect("query cache poisoned");
+            if let Some(rows) = cache.get(&key) {
+                return Ok(rows.clone());
+            }
+
+            let rows = unsafe { run_query(&key.0, &key.1) }?;
+            cache.insert(key, rows.clone());
+            Ok(rows)
+        },
+    );
+}
+
+/// Run `sql` through SPI in read-only mode, so anything but a read-only
+/// statement fails, and convert the rows like the COPY's own. The
+/// transaction is made read-only for the call too, so that volatile
+/// functions the statement calls (`nextval()`, a function that writes)
+/// fail as well.
+///
+/// # Safety
+/// Must run inside a Postgres backend, on its main thread.
+unsafe fn run_query(sql: &str, args: &[Value]) -> Result<Value, Error> {
+    let sql = CString::new(sql).map_err(|e| {
+        Error::new(ErrorKind::InvalidOperation, "invalid query() statement").with_source(e)
+    })?;
+
+    let mut arg_types = Vec::with_capacity(args.len());
+    let mut arg_values = Vec::with_capacity(args.len());
+    let mut arg_nulls = Vec::with_capacity(args.len());
+    for arg in args {
+        let (type_oid, datum) = query_parameter(arg);
+        arg_types.push(type_oid);
+        arg_values.push(datum.unwrap_or(Datum::from(0)));
+        arg_nulls.push(if datum.is_some() { b' ' } else { b'n' } as _);
+    }
+
+    let code = SPI_connect();
+    if code < 0 {
+        return Err(spi_error(code));
+    }
+
+    let code = {
+        let _read_only = ReadOnlyTransaction::start();
+        SPI_execute_with_args(
+            sql.as_ptr(),
+            args.len() as _,
+            arg_types.as_mut_ptr(),
+            arg_values.as_mut_ptr(),
+            arg_nulls.as_ptr(),
+            true,
+            0,
+        )
+    };
+    if code < 0 {
+        SPI_finish();
+        return Err(spi_error(code));
+    }
+
+    let mut rows = Vec::new();
+    let tuptable = SPI_tuptable;
+    if !tuptable.is_null() {
+        let tupdesc = pgrx::PgTupleDesc::from_pg_unchecked((*tuptable).tupdesc);
+        let mut convs = Vec::with_capacity(tupdesc.len());
+        let mut names = Vec::with_capacity(tupdesc.len());
+        for attribute in tupdesc.iter() {
+            names.push(attribute.name().to_string().into_boxed_str());
+            convs.push(column_conv_for(
+                attribute.type_oid().value().into(),
+                CurrentMemoryContext,
+            ));
+        }
+        let keys = Arc::new(RowKeys::new(names));
+
+        let tuples = std::slice::from_raw_parts((*tuptable).vals, (*tuptable).numvals as usize);
+        for tuple in tuples {
+            let mut values = Vec::with_capacity(convs.len());
+            for (idx, conv) in convs.iter_mut().enumerate() {
+                let mut is_null = false;
+                let datum =
+                    SPI_getbinval(*tuple, (*tuptable).tupdesc, idx as i32 + 1, &mut is_null);
+                values.push(if is_null {
+                    Value::from(())
+                } else {
+                    convert_datum(datum, conv)
+                });
+            }
+            rows.push(keys.row(values));
+        }
+    }
+
+    SPI_finish();
+
+    Ok(Value::from(rows))
+}
+
+/// An SPI failure code as a render error
+unsafe fn spi_error(code: i32) -> Error {
+    let code = CStr::from_ptr(SPI_result_code_string(code)).to_string_lossy();
+    Error::new(
+        ErrorKind::InvalidOperation,
+        format!("query() failed: {}", code),
+    )
+}
+
+/// Makes the transaction read-only until dropped, including when an ERROR
+/// unwinds through the query.
+struct ReadOnlyTransaction {
+    was_read_only: bool,
+}
+
+impl ReadOnlyTransaction {
+    unsafe fn start() -> Self {
+        let was_read_only = XactReadOnly;
+        XactReadOnly = true;
+        ReadOnlyTransaction { was_read_only }
+    }
+}
+
+impl Drop for ReadOnlyTransaction {
+    fn drop(&mut self) {
+        unsafe { XactReadOnly = self.was_read_only };
+    }
+}
+
+/// The type and value a template argument is bound as: booleans and
+/// numbers keep their type, anything else is an untyped literal (lists and
+/// maps as JSON), resolved from how the statement uses it, so `id = $1`
+/// works whatever the column's type. None is a NULL.
+unsafe fn query_parameter(value: &Value) -> (pg_sys::Oid, Option<Datum>) {
+    match value.kind() {
+        ValueKind::None | ValueKind::Undefined => (UNKNOWNOID, None),
+        ValueKind::Bool => (BOOLOID, value.is_true().into_datum()),
+        ValueKind::Number => match value.as_i64() {
+            Some(number) => (INT8OID, number.into_datum()),
+            None => (FLOAT8OID, f64::try_from(value.clone()).ok().into_datum()),
+        },
+        ValueKind::Seq | ValueKind::Map => (
+            UNKNOWNOID,
+            serde_json::to_string(value)
+                .ok()
+                .map(|json| Datum::from(json.as_pg_cstr())),
+        ),
+        _ => (
+            UNKNOWNOID,
+            Some(Datum::from(value.to_string().as_pg_cstr())),
+        ),
+    }
+}
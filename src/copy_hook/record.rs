@@ -0,0 +1,145 @@
+use std::io::{self, BufRead};
+
+/// How COPY FROM input is split into records, from the RECORD_DELIMITER
+/// option
+pub(crate) enum RecordDelimiter {
+    /// Records end at this byte string; the default is a newline, so each
+    /// line is a record
+    Bytes(Vec<u8>),
+    /// RECORD_DELIMITER '': records are separated by one or more blank
+    /// lines, like awk's paragraph mode
+    Paragraph,
+}
+
+impl RecordDelimiter {
+    pub fn from_option(value: &str) -> Self {
+        if value.is_empty() {
+            RecordDelimiter::Paragraph
+        } else {
+            RecordDelimiter::Bytes(value.as_bytes().to_vec())
+        }
+    }
+}
+
+impl Default for RecordDelimiter {
+    fn default() -> Self {
+        RecordDelimiter::Bytes(b"\n".to_vec())
+    }
+}
+
+/// Where a record was found in the input, for error messages
+#[derive(Clone, Copy)]
+pub(crate) struct RecordPosition {
+    pub first_line: u64,
+    pub line_count: u64,
+}
+
+/// Splits the input into records, keeping track of line numbers
+pub(crate) struct RecordReader<R> {
+    input: R,
+    delimiter: RecordDelimiter,
+    lines_read: u64,
+    line: Vec<u8>,
+}
+
+impl<R: BufRead> RecordReader<R> {
+    pub fn new(input: R, delimiter: RecordDelimiter) -> Self {
+        RecordReader {
+            input,
+            delimiter,
+            lines_read: 0,
+            line: Vec::new(),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.input
+    }
+
+    /// Read the next record into `record`, without its delimiter and
+    /// trailing line ending. Returns None at end of input.
+    pub fn next_record(&mut self, record: &mut Vec<u8>) -> io::Result<Option<RecordPosition>> {
+        record.clear();
+
+        let position = match &self.delimiter {
+            RecordDelimiter::Bytes(delimiter) => {
+                let first_line = self.lines_read + 1;
+                let last_byte = *delimiter.last().expect("record delimiter is not empty");
+
+                let mut read_any = false;
+                loop {
+                    let read = self.input.read_until(last_byte, record)?;
+                    if read == 0 {
+                        break;
+                    }
+                    read_any = true;
+                    if record.ends_with(delimiter) {
+                        record.truncate(record.len() - delimiter.len());
+                        self.lines_read += count_newlines(delimiter);
+                        break;
+                    }
+                }
+                if !read_any {
+                    return Ok(None);
+                }
+
+                self.lines_read += count_newlines(record);
+                strip_line_ending(record);
+
+                RecordPosition {
+                    first_line,
+                    line_count: count_newlines(record) + 1,
+                }
+            }
+            RecordDelimiter::Paragraph => {
+                let mut first_line = 0;
+                loop {
+                    self.line.clear();
+                    if self.input.read_until(b'\n', &mut self.line)? == 0 {
+                        break;
+                    }
+                    self.lines_read += 1;
+                    strip_line_ending(&mut self.line);
+
+                    if self.line.is_empty() {
+                        if first_line == 0 {
+                            // Blank lines before a record
+                            continue;
+                        }
+                        break;
+                    }
+
+                    if first_line == 0 {
+                        first_line = self.lines_read;
+                    } else {
+                        record.push(b'\n');
+                    }
+                    record.extend_from_slice(&self.line);
+                }
+                if first_line == 0 {
+                    return Ok(None);
+                }
+
+                RecordPosition {
+                    first_line,
+                    line_count: count_newlines(record) + 1,
+                }
+            }
+        };
+
+        Ok(Some(position))
+    }
+}
+
+fn count_newlines(bytes: &[u8]) -> u64 {
+    bytes.iter().filter(|&&byte| byte == b'\n').count() as u64
+}
+
+fn strip_line_ending(bytes: &mut Vec<u8>) {
+    if bytes.last() == Some(&b'\n') {
+        bytes.pop();
+    }
+    if bytes.last() == Some(&b'\r') {
+        bytes.pop();
+    }
+}
@@ -0,0 +1,379 @@
+use std::any::Any;
+use std::cell::Cell;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::Write;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use minijinja::{Environment, Error, ErrorKind, Value};
+use pgrx::pg_sys;
+use pgrx::GucSetting;
+
+use super::binary_output::BinaryOutput;
+use super::dest_receiver::{render_row, TEMPLATE_NAME};
+
+/// `pigiaminja.render_threads`: threads rendering rows, 1 rendering them on
+/// the backend itself.
+pub static RENDER_THREADS: GucSetting<i32> = GucSetting::<i32>::new(1);
+
+/// Rows handed to a render thread at a time, so the channels are not
+/// crossed once per row.
+const BATCH_ROWS: usize = 64;
+
+thread_local! {
+    static ON_RENDER_THREAD: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Fail unless running on the backend's own thread. Template functions that
+/// call into Postgres check this first; a row whose render fails it is
+/// rendered again on the backend.
+pub(crate) fn require_backend(what: &str) -> Result<(), Error> {
+    if ON_RENDER_THREAD.get() {
+        return Err(Error::new(
+            ErrorKind::InvalidOperation,
+            format!("{} cannot run on a render thread", what),
+        )
+        .with_source(NeedsBackend));
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+struct NeedsBackend;
+
+impl std::fmt::Display for NeedsBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("needs the backend")
+    }
+}
+
+impl std::error::Error for NeedsBackend {}
+
+fn needs_backend(error: &Error) -> bool {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(error);
+    while let Some(error) = source {
+        if error.is::<NeedsBackend>() {
+            return true;
+        }
+        source = error.source();
+    }
+    false
+}
+
+/// Blocks all signals on the current thread until dropped.
+struct SignalsBlocked {
+    previous: libc::sigset_t,
+}
+
+impl SignalsBlocked {
+    fn new() -> Self {
+        unsafe {
+            let mut all: libc::sigset_t = std::mem::zeroed();
+            let mut previous: libc::sigset_t = std::mem::zeroed();
+            libc::sigfillset(&mut all);
+            libc::pthread_sigmask(libc::SIG_BLOCK, &all, &mut previous);
+            SignalsBlocked { previous }
+        }
+    }
+}
+
+impl Drop for SignalsBlocked {
+    fn drop(&mut self) {
+        unsafe {
+            libc::pthread_sigmask(libc::SIG_SETMASK, &self.previous, std::ptr::null_mut());
+        }
+    }
+}
+
+/// The error a render thread's panic fails the COPY with
+fn render_panicked(panic: Box<dyn Any + Send>) -> Error {
+    let message = panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown cause");
+    Error::new(
+        ErrorKind::InvalidOperation,
+        format!("render thread panicked: {}", message),
+    )
+}
+
+/// A row as the backend hands it to a render thread
+struct Job {
+    row: Value,
+    rownum: u64,
+}
+
+/// What a render thread made of a row
+pub(super) enum Rendered {
+    Output(Vec<u8>),
+    Failed(Error),
+    /// The render went over `output_cap` and was cut short
+    TooLarge,
+    /// The template called something that only works on the backend
+    NeedsBackend {
+        row: Value,
+        rownum: u64,
+    },
+}
+
+/// Renders rows on a fixed set of threads while the backend keeps converting
+/// the next ones, handing the output back in row order. Render threads only
+/// ever see owned minijinja values and never call into Postgres.
+pub(super) struct RenderPool {
+    jobs: Option<Sender<(u64, Vec<Job>)>>,
+    results: Receiver<(u64, Vec<Rendered>)>,
+    workers: Vec<JoinHandle<()>>,
+    /// Stops renders in progress when the COPY is torn down
+    cancelled: Arc<AtomicBool>,
+    /// Rows collected for the next batch
+    batch: Vec<Job>,
+    next_batch: u64,
+    /// The batch whose rows come out next, and rendered batches waiting
+    /// their turn
+    next_output: u64,
+    pending: BTreeMap<u64, VecDeque<Rendered>>,
+    max_batches: u64,
+    /// Batch number past which a row needing the backend means the template
+    /// needs it for every row, rather than to fill a cache once
+    warmed_up: Option<u64>,
+    /// Whether the remaining rows should be rendered on the backend
+    serial: bool,
+}
+
+impl RenderPool {
+    /// Start `threads` render threads for `env`'s row template. `output_cap`
+    /// bounds one row's output, as the COPY's size limits would. With
+    /// BINARY_OUTPUT, rows come back with their `bytea` values restored.
+    pub fn new(
+        env: Arc<Environment<'static>>,
+        threads: usize,
+        preset: bool,
+        output_cap: Option<u64>,
+        binary_output: Option<BinaryOutput>,
+    ) -> Self {
+        let (job_sender, job_receiver) = mpsc::channel::<(u64, Vec<Job>)>();
+        let (result_sender, results) = mpsc::channel();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let cancelled = Arc::new(AtomicBool::new(false));
+
+        // Threads start with the spawning thread's signal mask: block every
+        // signal while spawning, so that Postgres' handlers (SIGINT, SIGTERM,
+        // SIGALRM for statement_timeout, SIGUSR1, ...) only ever run on the
+        // backend thread.
+        let signals = SignalsBlocked::new();
+        let workers = (0..threads)
+            .map(|_| {
+                let env = env.clone();
+                let job_receiver = job_receiver.clone();
+                let result_sender = result_sender.clone();
+                let cancelled = cancelled.clone();
+                let binary_output = binary_output.clone();
+                std::thread::spawn(move || {
+                    ON_RENDER_THREAD.set(true);
+                    let template = env
+                        .get_template(TEMPLATE_NAME)
+                        .expect("Pre-compiled template not found");
+
+                    loop {
+                        let next = job_receiver.lock().expect("job queue poisoned").recv();
+                        let Ok((batch, jobs)) = next else {
+                            return;
+                        };
+
+                        // A panic would lose the batch, and with it the rows
+                        // the backend is waiting on: it fails the COPY instead
+                        let rendered = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                            jobs.into_iter()
+                                .map(|job| {
+                                    let mut writer = CappedWriter {
+                                        out: Vec::new(),
+                                        cap: output_cap,
+                                        cancelled: &cancelled,
+                                        exceeded: false,
+                                        binary_output: binary_output.as_ref(),
+                                    };
+                                    let result = render_row(
+                                        &template,
+                                        preset,
+                                        job.row.clone(),
+                                        job.rownum,
+                                        &mut writer,
+                                    );
+                                    BinaryOutput::finish_row();
+                                    match result {
+                                        Ok(()) => Rendered::Output(writer.out),
+                                        Err(_) if writer.exceeded => Rendered::TooLarge,
+                                        Err(e) if needs_backend(&e) => Rendered::NeedsBackend {
+                                            row: job.row,
+                                            rownum: job.rownum,
+                                        },
+                                        Err(e) => Rendered::Failed(e),
+                                    }
+                                })
+                                .collect()
+                        }))
+                        .unwrap_or_else(|panic| {
+                            BinaryOutput::finish_row();
+                            vec![Rendered::Failed(render_panicked(panic))]
+                        });
+                        if result_sender.send((batch, rendered)).is_err() {
+                            return;
+                        }
+                    }
+                })
+            })
+            .collect();
+        drop(signals);
+
+        RenderPool {
+            jobs: Some(job_sender),
+            results,
+            workers,
+            cancelled,
+            batch: Vec::with_capacity(BATCH_ROWS),
+            next_batch: 0,
+            next_output: 0,
+            pending: BTreeMap::new(),
+            max_batches: 2 * threads as u64,
+            warmed_up: None,
+            serial: false,
+        }
+    }
+
+    /// Whether rows should now be rendered on the backend instead, once
+    /// the ones already queued are out
+    pub fn is_serial(&self) -> bool {
+        self.serial
+    }
+
+    /// Queue a row, waiting for earlier batches first when enough are
+    /// already in flight
+    pub fn push(&mut self, row: Value, rownum: u64) {
+        self.batch.push(Job { row, rownum });
+        if self.batch.len() >= BATCH_ROWS {
+            self.send_batch();
+        }
+    }
+
+    /// Send off the rows collected so far, however few
+    pub fn flush(&mut self) {
+        if !self.batch.is_empty() {
+            self.send_batch();
+        }
+    }
+
+    fn send_batch(&mut self) {
+        while self.next_batch - self.next_output >= self.max_batches
+            && !self.pending.contains_key(&self.next_output)
+        {
+            self.receive();
+        }
+
+        let jobs = std::mem::replace(&mut self.batch, Vec::with_capacity(BATCH_ROWS));
+        self.jobs
+            .as_ref()
+            .expect("render pool already shut down")
+            .send((self.next_batch, jobs))
+            .unwrap_or_else(|_| pgrx::error!("render threads exited unexpectedly"));
+        self.next_batch += 1;
+    }
+
+    /// The next row's output, in row order. With `wait`, blocks until every
+    /// batch sent has come back; otherwise returns None as soon as the next
+    /// one is not ready.
+    pub fn next(&mut self, wait: bool) -> Option<Rendered> {
+        loop {
+            if let Some(rows) = self.pending.get_mut(&self.next_output) {
+                let batch = self.next_output;
+                let Some(rendered) = rows.pop_front() else {
+                    self.pending.remove(&batch);
+                    self.next_output += 1;
+                    continue;
+                };
+
+                if matches!(rendered, Rendered::NeedsBackend { .. }) {
+                    match self.warmed_up {
+                        Some(warmed_up) if batch >= warmed_up => self.serial = true,
+                        Some(_) => {}
+                        None => self.warmed_up = Some(self.next_batch),
+                    }
+                }
+                return Some(rendered);
+            }
+
+            if !wait || self.next_output == self.next_batch {
+                return None;
+            }
+            self.receive();
+        }
+    }
+
+    /// Wait for a batch to come back, staying responsive to cancellation
+    fn receive(&mut self) {
+        loop {
+            match self.results.recv_timeout(Duration::from_millis(10)) {
+                Ok((batch, rendered)) => {
+                    self.pending.insert(batch, rendered.into());
+                    return;
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    pg_sys::check_for_interrupts!();
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    pgrx::error!("render threads exited unexpectedly")
+                }
+            }
+        }
+    }
+}
+
+impl Drop for RenderPool {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        // Closing the queue ends each thread once its batch is done
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Collects a row's output on a render thread, stopping the render when it
+/// outgrows the cap or the COPY is cancelled. The cap counts the bytes as
+/// they will be written, `bytea` values restored.
+struct CappedWriter<'a> {
+    out: Vec<u8>,
+    cap: Option<u64>,
+    cancelled: &'a AtomicBool,
+    exceeded: bool,
+    binary_output: Option<&'a BinaryOutput>,
+}
+
+impl Write for CappedWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return Err(std::io::Error::other("COPY cancelled"));
+        }
+        let bytes = match self.binary_output {
+            Some(binary_output) => binary_output.restore(buf),
+            None => buf.into(),
+        };
+        if let Some(cap) = self.cap {
+            if (self.out.len() + bytes.len()) as u64 > cap {
+                self.exceeded = true;
+                return Err(std::io::Error::other("row output exceeds the limit"));
+            }
+        }
+        self.out.extend_from_slice(&bytes);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,179 @@
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+
+use minijinja::value::Rest;
+use minijinja::{Error, ErrorKind, Value};
+use pgrx::pg_sys::{
+    self, fmgr_info_cxt, getTypeInputInfo, get_func_name, get_func_signature, Datum, FmgrInfo,
+    FunctionCallInfoBaseData, InputFunctionCall, MemoryContext, MemoryContextAllocZero,
+    NullableDatum, Oid, DEFAULT_COLLATION_OID,
+};
+
+use super::dest_receiver::{column_conv_for, convert_datum, ColumnConv};
+use super::pg_compat::check_function_execute_permission;
+use super::render_pool::require_backend;
+
+/// A SQL function registered as a template filter or test. Arguments are
+/// converted with their type's input function, the result like a column of
+/// the return type.
+pub(crate) struct SqlFunction {
+    name: String,
+    call: RefCell<FunctionCall>,
+}
+
+struct FunctionCall {
+    flinfo: FmgrInfo,
+    // Reused by every call, in the COPY's memory context
+    fcinfo: *mut FunctionCallInfoBaseData,
+    // Input function and its type I/O parameter, per argument
+    inputs: Vec<(FmgrInfo, Oid)>,
+    result: ColumnConv,
+}
+
+// Template functions are only ever called from the backend's own thread,
+// while it renders; minijinja just requires them to be Send + Sync.
+unsafe impl Send for SqlFunction {}
+unsafe impl Sync for SqlFunction {}
+
+impl SqlFunction {
+    /// Look up everything needed to call the function, once per COPY. The
+    /// caller must be allowed to execute it. Returns None if the function
+    /// has been dropped since it was registered.
+    ///
+    /// # Safety
+    /// Must run inside a Postgres backend (performs catalog lookups).
+    pub unsafe fn new(function_oid: Oid, memory_context: MemoryContext) -> Option<Self> {
+        let name = get_func_name(function_oid);
+        if name.is_null() {
+            return None;
+        }
+        let name = CStr::from_ptr(name).to_string_lossy().into_owned();
+
+        check_function_execute_permission(function_oid);
+
+        let mut arg_types = std::ptr::null_mut();
+        let mut nargs = 0;
+        let result_type = get_func_signature(function_oid, &mut arg_types, &mut nargs);
+
+        let mut flinfo: FmgrInfo = std::mem::zeroed();
+        fmgr_info_cxt(function_oid, &mut flinfo, memory_context);
+
+        let inputs = std::slice::from_raw_parts(arg_types, nargs as usize)
+            .iter()
+            .map(|arg_type| {
+                let mut typinput = Oid::INVALID;
+                let mut typioparam = Oid::INVALID;
+                getTypeInputInfo(*arg_type, &mut typinput, &mut typioparam);
+                let mut input: FmgrInfo = std::mem::zeroed();
+                fmgr_info_cxt(typinput, &mut input, memory_context);
+                (input, typioparam)
+            })
+            .collect();
+
+        let fcinfo = MemoryContextAllocZero(
+            memory_context,
+            std::mem::size_of::<FunctionCallInfoBaseData>()
+                + nargs as usize * std::mem::size_of::<NullableDatum>(),
+        ) as *mut FunctionCallInfoBaseData;
+        (*fcinfo).fncollation = DEFAULT_COLLATION_OID;
+        (*fcinfo).nargs = nargs as _;
+
+        Some(SqlFunction {
+            name,
+            call: RefCell::new(FunctionCall {
+                flinfo,
+                fcinfo,
+                inputs,
+                result: column_conv_for(result_type.into(), memory_context),
+            }),
+        })
+    }
+
+    /// Call the function with the filter's value and arguments
+    pub fn call(&self, args: Rest<Value>) -> Result<Value, Error> {
+        require_backend(&self.name)?;
+        let mut call = self.call.borrow_mut();
+        let call = &mut *call;
+
+        if args.len() != call.inputs.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidOperation,
+                format!(
+                    "{} takes {} argument(s), got {}",
+                    self.name,
+                    call.inputs.len(),
+                    args.len()
+                ),
+            ));
+        }
+
+        unsafe {
+            let fcinfo = call.fcinfo;
+            (*fcinfo).flinfo = &mut call.flinfo;
+            (*fcinfo).isnull = false;
+
+            let fcargs = (*fcinfo).args.as_mut_slice(call.inputs.len());
+            let mut has_null = false;
+            for ((arg, value), (input, typioparam)) in fcargs
+                .iter_mut()
+                .zip(args.iter())
+                .zip(call.inputs.iter_mut())
+            {
+                match input_string(value)? {
+                    Some(text) => {
+                        arg.value = InputFunctionCall(input, text.as_ptr() as _, *typioparam, -1);
+                        arg.isnull = false;
+                    }
+                    None => {
+                        arg.value = Datum::from(0);
+                        arg.isnull = true;
+                        has_null = true;
+                    }
+                }
+            }
+
+            // A strict function returns NULL for a NULL argument without
+            // being called
+            if has_null && call.flinfo.fn_strict {
+                return Ok(Value::from(()));
+            }
+
+            let fn_addr = call.flinfo.fn_addr.expect("function has no address");
+            let result = pg_sys::ffi::pg_guard_ffi_boundary(|| fn_addr(fcinfo));
+            if (*fcinfo).isnull {
+                return Ok(Value::from(()));
+            }
+
+            Ok(convert_datum(result, &mut call.result))
+        }
+    }
+}
+
+/// The text an argument is parsed from: strings and numbers as they render,
+/// lists and maps as JSON. None and undefined are NULL.
+fn input_string(value: &Value) -> Result<Option<CString>, Error> {
+    if value.is_none() || value.is_undefined() {
+        return Ok(None);
+    }
+
+    let text = match value.kind() {
+        minijinja::value::ValueKind::Seq | minijinja::value::ValueKind::Map => {
+            serde_json::to_string(value).map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidOperation,
+                    "cannot pass value to SQL function",
+                )
+                .with_source(e)
+            })?
+        }
+        _ => value.to_string(),
+    };
+
+    CString::new(text).map(Some).map_err(|e| {
+        Error::new(
+            ErrorKind::InvalidOperation,
+            "cannot pass value to SQL function",
+        )
+        .with_source(e)
+    })
+}
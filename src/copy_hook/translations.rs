@@ -0,0 +1,430 @@
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::sync::{Arc, OnceLock};
+
+use minijinja::value::Kwargs;
+use minijinja::{Environment, Error};
+use pgrx::pg_sys::{AsPgCStr, GetConfigOption};
+use pgrx::Spi;
+
+use super::render_pool::require_backend;
+
+pgrx::extension_sql!(
+    r#"
+CREATE SCHEMA IF NOT EXISTS pigiaminja;
+
+-- Message catalogs for the _(), gettext() and ngettext() template functions.
+-- As in a .po file, the entry with an empty msgid is the catalog header, and
+-- its Plural-Forms line numbers the plural forms: msgstr is form 0 and
+-- plural_forms holds forms 1, 2, ...
+CREATE TABLE pigiaminja.translations (
+    domain text NOT NULL DEFAULT 'messages',
+    locale text NOT NULL,
+    msgid text NOT NULL,
+    msgstr text NOT NULL,
+    plural_forms text[],
+    PRIMARY KEY (domain, locale, msgid)
+);
+-- Rows are user data: have pg_dump keep them along with the extension.
+SELECT pg_catalog.pg_extension_config_dump('pigiaminja.translations', '');
+
+GRANT SELECT ON pigiaminja.translations TO PUBLIC;
+"#,
+    name = "translations",
+);
+
+const DEFAULT_DOMAIN: &str = "messages";
+
+/// Messages of one locale, loaded from pigiaminja.translations
+#[derive(Default)]
+struct Catalog {
+    messages: HashMap<(String, String), Message>,
+    plural_rules: HashMap<String, PluralRule>,
+}
+
+struct Message {
+    msgstr: String,
+    plural_forms: Vec<String>,
+}
+
+/// The catalog a COPY renders with, loaded the first time a template asks
+/// for a translation, so exports that don't translate don't pay for it.
+struct Translations {
+    locales: Vec<String>,
+    catalog: OnceLock<Catalog>,
+}
+
+impl Translations {
+    fn catalog(&self) -> Result<&Catalog, Error> {
+        if let Some(catalog) = self.catalog.get() {
+            return Ok(catalog);
+        }
+
+        require_backend("loading translations")?;
+        Ok(self.catalog.get_or_init(|| {
+            load_catalog(&self.locales)
+                .unwrap_or_else(|e| pgrx::error!("Failed to load translations: {}", e))
+        }))
+    }
+
+    fn gettext(&self, msgid: &str, kwargs: &Kwargs) -> Result<String, Error> {
+        let domain = domain(kwargs)?;
+        let translated = self
+            .catalog()?
+            .messages
+            .get(&(domain, msgid.to_string()))
+            .map(|message| message.msgstr.clone());
+        Ok(translated.unwrap_or_else(|| msgid.to_string()))
+    }
+
+    fn ngettext(
+        &self,
+        singular: &str,
+        plural: &str,
+        n: i64,
+        kwargs: &Kwargs,
+    ) -> Result<String, Error> {
+        let domain = domain(kwargs)?;
+        let catalog = self.catalog()?;
+
+        let Some(message) = catalog
+            .messages
+            .get(&(domain.clone(), singular.to_string()))
+        else {
+            return Ok(if n == 1 { singular } else { plural }.to_string());
+        };
+
+        let form = catalog
+            .plural_rules
+            .get(&domain)
+            .map_or(default_plural_form(n), |rule| rule.evaluate(n));
+        let translated = match form {
+            0 => Some(&message.msgstr),
+            form => usize::try_from(form - 1)
+                .ok()
+                .and_then(|index| message.plural_forms.get(index)),
+        };
+        Ok(translated.unwrap_or(&message.msgstr).clone())
+    }
+}
+
+fn domain(kwargs: &Kwargs) -> Result<String, Error> {
+    let domain: Option<String> = kwargs.get("domain")?;
+    kwargs.assert_all_used()?;
+    Ok(domain.unwrap_or_else(|| DEFAULT_DOMAIN.to_string()))
+}
+
+/// Add `_()`, `gettext()` and `ngettext()`, translating into `locale` (the
+/// LOCALE option) or else lc_messages. A message without a translation
+/// renders as written.
+pub(crate) fn add_translation_functions(env: &mut Environment<'static>, locale: Option<&str>) {
+    let locale = match locale {
+        Some(locale) => locale.to_string(),
+        None => unsafe {
+            let lc_messages = GetConfigOption("lc_messages".as_pg_cstr(), true, false);
+            if lc_messages.is_null() {
+                String::new()
+            } else {
+                CStr::from_ptr(lc_messages).to_string_lossy().into_owned()
+            }
+        },
+    };
+
+    let translations = Arc::new(Translations {
+        locales: locale_fallbacks(&locale),
+        catalog: OnceLock::new(),
+    });
+
+    for name in ["_", "gettext"] {
+        let translations = translations.clone();
+        env.add_function(name, move |msgid: String, kwargs: Kwargs| {
+            translations.gettext(&msgid, &kwargs)
+        });
+    }
+
+    env.add_function(
+        "ngettext",
+        move |singular: String, plural: String, n: i64, kwargs: Kwargs| {
+            translations.ngettext(&singular, &plural, n, &kwargs)
+        },
+    );
+}
+
+/// The catalogs to look in, most specific first: `de_DE.UTF-8@euro`, then
+/// `de_DE`, then `de`
+fn locale_fallbacks(locale: &str) -> Vec<String> {
+    let mut locales = vec![locale.to_string()];
+
+    let without_codeset = locale.split(['.', '@']).next().unwrap_or_default();
+    let language = without_codeset.split('_').next().unwrap_or_default();
+    for fallback in [without_codeset, language] {
+        if !fallback.is_empty() && !locales.iter().any(|l| l == fallback) {
+            locales.push(fallback.to_string());
+        }
+    }
+
+    locales
+}
+
+/// Read the messages for `locales`, a more specific locale's entries winning
+/// over a fallback's
+fn load_catalog(locales: &[String]) -> Result<Catalog, pgrx::spi::Error> {
+    Spi::connect(|client| {
+        let mut catalog = Catalog::default();
+
+        // The hook works without CREATE EXTENSION, and then there's no table
+        let installed = client
+            .select(
+                "SELECT to_regclass('pigiaminja.translations') IS NOT NULL",
+                None,
+                &[],
+            )?
+            .first()
+            .get_one::<bool>()?
+            .unwrap_or(false);
+        if !installed {
+            return Ok(catalog);
+        }
+
+        let rows = client.select(
+            "SELECT domain, msgid, msgstr, plural_forms
+             FROM pigiaminja.translations
+             WHERE locale = ANY($1)
+             ORDER BY array_position($1, locale) DESC",
+            None,
+            &[locales.to_vec().into()],
+        )?;
+
+        for row in rows {
+            let domain = row.get::<String>(1)?.unwrap_or_default();
+            let msgid = row.get::<String>(2)?.unwrap_or_default();
+            let msgstr = row.get::<String>(3)?.unwrap_or_default();
+            let plural_forms = row
+                .get::<Vec<Option<String>>>(4)?
+                .unwrap_or_default()
+                .into_iter()
+                .map(Option::unwrap_or_default)
+                .collect();
+
+            if msgid.is_empty() {
+                if let Some(rule) = PluralRule::from_header(&msgstr) {
+                    catalog.plural_rules.insert(domain, rule);
+                }
+                continue;
+            }
+
+            // Rows come least specific first, so later ones override
+            catalog.messages.insert(
+                (domain, msgid),
+                Message {
+                    msgstr,
+                    plural_forms,
+                },
+            );
+        }
+
+        Ok(catalog)
+    })
+}
+
+/// The `plural=` expression of a catalog's Plural-Forms header, the C
+/// expression gettext evaluates to pick a plural form for `n`
+#[derive(Debug)]
+enum PluralRule {
+    N,
+    Number(i64),
+    Not(Box<PluralRule>),
+    Binary(Box<PluralRule>, BinaryOp, Box<PluralRule>),
+    Conditional(Box<PluralRule>, Box<PluralRule>, Box<PluralRule>),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum BinaryOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+impl PluralRule {
+    /// Parse the rule out of a catalog header's `Plural-Forms: nplurals=2;
+    /// plural=(n != 1);` line. None if the header has no valid rule.
+    fn from_header(header: &str) -> Option<Self> {
+        let plural_forms = header
+            .lines()
+            .find_map(|line| line.trim().strip_prefix("Plural-Forms:"))?;
+        let expression = plural_forms
+            .split(';')
+            .find_map(|part| part.trim().strip_prefix("plural="))?;
+
+        let mut parser = RuleParser {
+            input: expression.as_bytes(),
+            pos: 0,
+        };
+        let rule = parser.conditional()?;
+        parser.skip_spaces();
+        (parser.pos == parser.input.len()).then_some(rule)
+    }
+
+    fn evaluate(&self, n: i64) -> i64 {
+        match self {
+            PluralRule::N => n,
+            PluralRule::Number(number) => *number,
+            PluralRule::Not(operand) => (operand.evaluate(n) == 0) as i64,
+            PluralRule::Binary(left, op, right) => {
+                let left = left.evaluate(n);
+                // Short-circuit like C
+                match op {
+                    BinaryOp::Or if left != 0 => return 1,
+                    BinaryOp::And if left == 0 => return 0,
+                    _ => {}
+                }
+                let right = right.evaluate(n);
+                match op {
+                    BinaryOp::Or | BinaryOp::And => (right != 0) as i64,
+                    BinaryOp::Eq => (left == right) as i64,
+                    BinaryOp::Ne => (left != right) as i64,
+                    BinaryOp::Lt => (left < right) as i64,
+                    BinaryOp::Le => (left <= right) as i64,
+                    BinaryOp::Gt => (left > right) as i64,
+                    BinaryOp::Ge => (left >= right) as i64,
+                    BinaryOp::Add => left.wrapping_add(right),
+                    BinaryOp::Sub => left.wrapping_sub(right),
+                    BinaryOp::Mul => left.wrapping_mul(right),
+                    BinaryOp::Div => left.checked_div(right).unwrap_or(0),
+                    BinaryOp::Rem => left.checked_rem(right).unwrap_or(0),
+                }
+            }
+            PluralRule::Conditional(condition, then, otherwise) => {
+                if condition.evaluate(n) != 0 {
+                    then.evaluate(n)
+                } else {
+                    otherwise.evaluate(n)
+                }
+            }
+        }
+    }
+}
+
+/// The plural form for `n` without a Plural-Forms header: `plural=(n != 1)`
+fn default_plural_form(n: i64) -> i64 {
+    (n != 1) as i64
+}
+
+/// Recursive descent over C's precedence levels, as far as plural rules use
+/// them
+struct RuleParser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl RuleParser<'_> {
+    fn skip_spaces(&mut self) {
+        while self
+            .input
+            .get(self.pos)
+            .is_some_and(u8::is_ascii_whitespace)
+        {
+            self.pos += 1;
+        }
+    }
+
+    /// Consume `token` if it comes next
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_spaces();
+        if self.input[self.pos..].starts_with(token.as_bytes()) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn conditional(&mut self) -> Option<PluralRule> {
+        let condition = self.binary(0)?;
+        if !self.eat("?") {
+            return Some(condition);
+        }
+        let then = self.conditional()?;
+        if !self.eat(":") {
+            return None;
+        }
+        let otherwise = self.conditional()?;
+        Some(PluralRule::Conditional(
+            Box::new(condition),
+            Box::new(then),
+            Box::new(otherwise),
+        ))
+    }
+
+    /// Operators by precedence, lowest first. Longer tokens come before
+    /// their prefixes so `<=` isn't read as `<`.
+    const LEVELS: [&'static [(&'static str, BinaryOp)]; 6] = [
+        &[("||", BinaryOp::Or)],
+        &[("&&", BinaryOp::And)],
+        &[("==", BinaryOp::Eq), ("!=", BinaryOp::Ne)],
+        &[
+            ("<=", BinaryOp::Le),
+            (">=", BinaryOp::Ge),
+            ("<", BinaryOp::Lt),
+            (">", BinaryOp::Gt),
+        ],
+        &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+        &[
+            ("*", BinaryOp::Mul),
+            ("/", BinaryOp::Div),
+            ("%", BinaryOp::Rem),
+        ],
+    ];
+
+    fn binary(&mut self, level: usize) -> Option<PluralRule> {
+        let Some(operators) = Self::LEVELS.get(level) else {
+            return self.unary();
+        };
+
+        let mut left = self.binary(level + 1)?;
+        'operators: loop {
+            for (token, op) in operators.iter() {
+                if self.eat(token) {
+                    let right = self.binary(level + 1)?;
+                    left = PluralRule::Binary(Box::new(left), *op, Box::new(right));
+                    continue 'operators;
+                }
+            }
+            return Some(left);
+        }
+    }
+
+    fn unary(&mut self) -> Option<PluralRule> {
+        if self.eat("!") {
+            return Some(PluralRule::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let inner = self.conditional()?;
+            return self.eat(")").then_some(inner);
+        }
+        if self.eat("n") {
+            return Some(PluralRule::N);
+        }
+
+        self.skip_spaces();
+        let start = self.pos;
+        while self.input.get(self.pos).is_some_and(u8::is_ascii_digit) {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.input[start..self.pos])
+            .ok()?
+            .parse()
+            .ok()
+            .map(PluralRule::Number)
+    }
+}
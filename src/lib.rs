@@ -1,6 +1,11 @@
 use std::ffi::CStr;
 
+use copy_hook::dest_receiver::{
+    COPY_MESSAGE_SIZE, MAX_FUEL, MAX_OUTPUT_BYTES, MAX_RECURSION, MAX_ROW_OUTPUT_BYTES,
+};
 use copy_hook::hook::{init_jinja_copy_hook, ENABLE_JINJA_COPY_HOOK};
+use copy_hook::query_function::ENABLE_QUERY_FUNCTION;
+use copy_hook::render_pool::RENDER_THREADS;
 use pgrx::pg_sys::AsPgCStr;
 use pgrx::{prelude::*, GucContext, GucFlags, GucRegistry};
 
@@ -21,7 +26,103 @@ pub extern "C-unwind" fn _PG_init() {
             &ENABLE_JINJA_COPY_HOOK,
             GucContext::Userset,
             GucFlags::default(),
-        )
+        );
+
+        GucRegistry::define_bool_guc(
+            CStr::from_ptr("pigiaminja.enable_query_function".as_pg_cstr()),
+            CStr::from_ptr("Allow templates to run queries".as_pg_cstr()),
+            CStr::from_ptr(
+                "Makes the query() template function available. Queries run read-only, with the privileges of the user running the COPY."
+                    .as_pg_cstr(),
+            ),
+            &ENABLE_QUERY_FUNCTION,
+            GucContext::Userset,
+            GucFlags::default(),
+        );
+
+        GucRegistry::define_int_guc(
+            CStr::from_ptr("pigiaminja.max_fuel".as_pg_cstr()),
+            CStr::from_ptr("Fuel available to render a single row".as_pg_cstr()),
+            CStr::from_ptr(
+                "Upper bound on the template instructions executed per row, so a runaway template fails instead of running unbounded. 0 means unlimited."
+                    .as_pg_cstr(),
+            ),
+            &MAX_FUEL,
+            0,
+            i32::MAX,
+            GucContext::Userset,
+            GucFlags::default(),
+        );
+
+        GucRegistry::define_int_guc(
+            CStr::from_ptr("pigiaminja.max_recursion".as_pg_cstr()),
+            CStr::from_ptr("Template recursion limit".as_pg_cstr()),
+            CStr::from_ptr(
+                "Maximum nesting depth of loops, macros and includes while rendering a row."
+                    .as_pg_cstr(),
+            ),
+            &MAX_RECURSION,
+            1,
+            500,
+            GucContext::Userset,
+            GucFlags::default(),
+        );
+
+        GucRegistry::define_int_guc(
+            CStr::from_ptr("pigiaminja.render_threads".as_pg_cstr()),
+            CStr::from_ptr("Threads rendering the rows of a jinja COPY".as_pg_cstr()),
+            CStr::from_ptr(
+                "Rows are rendered on this many threads while the backend reads the next ones, and written out in order. 1 renders them on the backend itself."
+                    .as_pg_cstr(),
+            ),
+            &RENDER_THREADS,
+            1,
+            64,
+            GucContext::Userset,
+            GucFlags::default(),
+        );
+
+        GucRegistry::define_int_guc(
+            CStr::from_ptr("pigiaminja.max_output_bytes".as_pg_cstr()),
+            CStr::from_ptr("Maximum output of a single jinja COPY".as_pg_cstr()),
+            CStr::from_ptr(
+                "A COPY whose rendered output would exceed this size fails. 0 means unlimited."
+                    .as_pg_cstr(),
+            ),
+            &MAX_OUTPUT_BYTES,
+            0,
+            i32::MAX,
+            GucContext::Suset,
+            GucFlags::UNIT_KB,
+        );
+
+        GucRegistry::define_int_guc(
+            CStr::from_ptr("pigiaminja.max_row_output_bytes".as_pg_cstr()),
+            CStr::from_ptr("Maximum output of a single rendered row".as_pg_cstr()),
+            CStr::from_ptr(
+                "A COPY fails when rendering one row would exceed this size. 0 means unlimited."
+                    .as_pg_cstr(),
+            ),
+            &MAX_ROW_OUTPUT_BYTES,
+            0,
+            i32::MAX,
+            GucContext::Suset,
+            GucFlags::UNIT_KB,
+        );
+
+        GucRegistry::define_int_guc(
+            CStr::from_ptr("pigiaminja.copy_message_size".as_pg_cstr()),
+            CStr::from_ptr("Output sent per CopyData message".as_pg_cstr()),
+            CStr::from_ptr(
+                "COPY TO STDOUT collects rendered rows into one protocol message until it reaches this size. 0 sends every row in its own message."
+                    .as_pg_cstr(),
+            ),
+            &COPY_MESSAGE_SIZE,
+            0,
+            8 * 1024,
+            GucContext::Userset,
+            GucFlags::UNIT_KB,
+        );
     };
 
     init_jinja_copy_hook();
@@ -32,6 +32,19 @@ mod tests {
         assert_eq!(setting, Ok(Some("on")));
     }
 
+    #[pg_test]
+    fn test_render_limit_gucs() {
+        // Fuel is off by default; recursion matches minijinja's own default
+        let setting = Spi::get_one::<&str>("SHOW pigiaminja.max_fuel");
+        assert_eq!(setting, Ok(Some("0")));
+        let setting = Spi::get_one::<&str>("SHOW pigiaminja.max_recursion");
+        assert_eq!(setting, Ok(Some("500")));
+
+        // Above 500 minijinja would silently cap it, so reject it up front
+        let result = Spi::run("SET pigiaminja.max_recursion = 501");
+        assert!(result.is_err(), "max_recursion above 500 should be rejected");
+    }
+
     #[pg_test]
     fn test_copy_hook_functions_exist() {
         // Test that our internal functions are available (via procedural checks)
@@ -188,6 +201,109 @@ mod tests {
         fs::remove_file(output_path).expect("Should clean up test file");
     }
 
+    #[pg_test(error = "program \"cat > /dev/null; echo boom >&2; exit 3\" failed")]
+    fn test_copy_to_program_failure_is_reported() {
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+
+        // A program exiting non-zero must fail the COPY instead of reporting success
+        let _ = Spi::run(
+            "COPY (SELECT 1 AS x) TO PROGRAM 'cat > /dev/null; echo boom >&2; exit 3'
+             (FORMAT 'jinja', TEMPLATE '{{ row.x }}')",
+        );
+    }
+
+    #[pg_test]
+    fn test_copy_to_program_failure_reports_stderr() {
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+
+        // The error detail carries the exit status and the tail of stderr
+        Spi::run(
+            r#"CREATE FUNCTION pg_temp.program_failure_detail() RETURNS text LANGUAGE plpgsql AS $$
+            DECLARE
+                detail text;
+            BEGIN
+                COPY (SELECT 1 AS x) TO PROGRAM 'cat > /dev/null; echo boom >&2; exit 3'
+                    (FORMAT 'jinja', TEMPLATE '{{ row.x }}');
+                RETURN NULL;
+            EXCEPTION WHEN external_routine_exception THEN
+                GET STACKED DIAGNOSTICS detail = PG_EXCEPTION_DETAIL;
+                RETURN detail;
+            END
+            $$"#,
+        )
+        .expect("Failed to create function");
+
+        let detail = Spi::get_one::<String>("SELECT pg_temp.program_failure_detail()")
+            .expect("Failed to run COPY")
+            .expect("COPY should have failed");
+        assert_eq!(
+            detail,
+            "child process exited with exit code 3\nProgram stderr:\nboom"
+        );
+    }
+
+    #[pg_test(error = "jinja COPY output exceeds max_row_output_bytes (1024 bytes)")]
+    fn test_row_output_limit() {
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+        Spi::run("SET pigiaminja.max_row_output_bytes = '1kB'").expect("Failed to set GUC");
+
+        let _ = Spi::run(
+            "COPY (SELECT 1 AS x) TO '/tmp/pgrx_test_row_output_limit.txt'
+             (FORMAT 'jinja', TEMPLATE '{% for i in range(2000) %}x{% endfor %}')",
+        );
+    }
+
+    #[pg_test]
+    fn test_output_limit_copy_option() {
+        use std::fs;
+
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+
+        let output_path = "/tmp/pgrx_test_output_limit_option.txt";
+        let _ = fs::remove_file(output_path);
+
+        // 2 rows of 700 bytes fit in 2kB (a third one does not, see below)
+        let query = format!(
+            "COPY (SELECT i FROM generate_series(1, 2) AS s(i)) TO '{}'
+             (FORMAT 'jinja', MAX_OUTPUT_BYTES '2kB',
+              TEMPLATE '{{% for i in range(700) %}}x{{% endfor %}}')",
+            output_path
+        );
+        Spi::run(&query).expect("COPY within MAX_OUTPUT_BYTES should succeed");
+
+        let contents = fs::read_to_string(output_path).expect("Should read output file");
+        assert_eq!(contents.len(), 1400);
+
+        fs::remove_file(output_path).expect("Should clean up test file");
+    }
+
+    #[pg_test(error = "jinja COPY output exceeds max_output_bytes (2048 bytes)")]
+    fn test_output_limit_copy_option_exceeded() {
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+
+        // The third row of 700 bytes takes the export past 2kB
+        let _ = Spi::run(
+            "COPY (SELECT i FROM generate_series(1, 3) AS s(i)) TO '/tmp/pgrx_test_output_limit_exceeded.txt'
+             (FORMAT 'jinja', MAX_OUTPUT_BYTES '2kB',
+              TEMPLATE '{% for i in range(700) %}x{% endfor %}')",
+        );
+    }
+
+    #[pg_test(error = "permission denied to raise MAX_OUTPUT_BYTES")]
+    fn test_output_limit_raise_requires_superuser() {
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+        Spi::run("SET pigiaminja.max_output_bytes = '1MB'").expect("Failed to set GUC");
+
+        // Tightening the cap is fine for anyone, lifting it is not
+        Spi::run("CREATE ROLE pigiaminja_no_limit_priv").expect("Failed to create role");
+        Spi::run("SET ROLE pigiaminja_no_limit_priv").expect("Failed to set role");
+
+        let _ = Spi::run(
+            "COPY (SELECT 1 AS x) TO '/tmp/pgrx_test_output_limit_denied.txt'
+             (FORMAT 'jinja', MAX_OUTPUT_BYTES 0, TEMPLATE '{{ row.x }}')",
+        );
+    }
+
     #[pg_test(error = "permission denied to COPY to a file")]
     fn test_copy_to_file_requires_privilege() {
         Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
@@ -217,4 +333,992 @@ mod tests {
              (FORMAT 'jinja', TEMPLATE '{{ row.x }}')",
         );
     }
+
+    #[pg_test]
+    fn test_copy_from_file_with_pattern() {
+        use std::fs;
+
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+
+        Spi::run("CREATE TEMP TABLE test_file_input (name TEXT, value INTEGER)")
+            .expect("Failed to create temp table");
+
+        let input_path = "/tmp/pgrx_test_copy_from_file.txt";
+        fs::write(input_path, "Alice 100\nBob 20\nCarol 300\n").expect("Should write input file");
+
+        // Named groups map onto columns, and the WHERE clause filters rows
+        let query = format!(
+            r"COPY test_file_input FROM '{}' (FORMAT 'jinja', PATTERN '^(?P<name>\w+) (?P<value>\d+)$') WHERE value > 50",
+            input_path
+        );
+        Spi::run(&query).expect("COPY FROM FILE should succeed");
+
+        let rows = Spi::get_one::<String>(
+            "SELECT string_agg(name || '=' || value, ',' ORDER BY name) FROM test_file_input",
+        )
+        .expect("Failed to read imported rows");
+        assert_eq!(rows.as_deref(), Some("Alice=100,Carol=300"));
+
+        fs::remove_file(input_path).expect("Should clean up test file");
+    }
+
+    #[pg_test]
+    fn test_copy_from_program_with_template() {
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+
+        Spi::run("CREATE TEMP TABLE test_program_input (id INTEGER, label TEXT)")
+            .expect("Failed to create temp table");
+
+        // Placeholders become capture groups, the text around them must match
+        Spi::run(
+            r#"COPY test_program_input FROM PROGRAM 'printf ''id=1;label=a\\b\nid=2;label=\n'''
+             (FORMAT 'jinja', TEMPLATE 'id={{ row.id }};label={{ label }}')"#,
+        )
+        .expect("COPY FROM PROGRAM should succeed");
+
+        let rows = Spi::get_one::<String>(
+            "SELECT string_agg(id || ':' || label, ',' ORDER BY id) FROM test_program_input",
+        )
+        .expect("Failed to read imported rows");
+        assert_eq!(rows.as_deref(), Some("1:a\\b,2:"));
+    }
+
+    #[pg_test(error = "unsupported placeholder in import template: \" {{ b|upper }} \"")]
+    fn test_copy_from_rejects_unsupported_middle_placeholder() {
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+
+        Spi::run("CREATE TEMP TABLE test_middle_placeholder (a TEXT, b TEXT, c TEXT)")
+            .expect("Failed to create temp table");
+
+        // Between two supported placeholders, it must not become literal text
+        let _ = Spi::run(
+            r#"COPY test_middle_placeholder FROM PROGRAM 'printf ''x y z\n'''
+             (FORMAT 'jinja', TEMPLATE '{{ a }} {{ b|upper }} {{ c }}')"#,
+        );
+    }
+
+    #[pg_test(error = "line 2 does not match the import pattern")]
+    fn test_copy_from_rejects_unmatched_line() {
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+
+        Spi::run("CREATE TEMP TABLE test_unmatched_input (value INTEGER)")
+            .expect("Failed to create temp table");
+
+        let _ = Spi::run(
+            r#"COPY test_unmatched_input FROM PROGRAM 'printf ''1\nx\n'''
+             (FORMAT 'jinja', PATTERN '^(?P<value>\d+)$')"#,
+        );
+    }
+
+    #[pg_test]
+    fn test_copy_from_multi_line_records() {
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+
+        Spi::run("CREATE TEMP TABLE test_stanza_input (host TEXT, port INTEGER)")
+            .expect("Failed to create temp table");
+
+        // Blank lines separate stanzas; the malformed one is skipped
+        Spi::run(
+            r#"COPY test_stanza_input FROM PROGRAM 'printf ''host: a\nport: 1\n\n\nhost: b\n\nhost: c\nport: 3\n'''
+             (FORMAT 'jinja', RECORD_DELIMITER '', LOG_ERRORS true,
+              TEMPLATE 'host: {{ host }}
+port: {{ port }}')"#,
+        )
+        .expect("COPY FROM with RECORD_DELIMITER should succeed");
+
+        let rows = Spi::get_one::<String>(
+            "SELECT string_agg(host || ':' || port, ',' ORDER BY host) FROM test_stanza_input",
+        )
+        .expect("Failed to read imported rows");
+        assert_eq!(rows.as_deref(), Some("a:1,c:3"));
+    }
+
+    #[pg_test(error = "record at lines 4-5 does not match the import pattern")]
+    fn test_copy_from_reports_record_lines() {
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+
+        Spi::run("CREATE TEMP TABLE test_record_input (a INTEGER, b INTEGER)")
+            .expect("Failed to create temp table");
+
+        let _ = Spi::run(
+            r#"COPY test_record_input FROM PROGRAM 'printf ''1\n2\n--\n3\nx\n'''
+             (FORMAT 'jinja', RECORD_DELIMITER E'\n--\n',
+              PATTERN '^(?P<a>\d+)\n(?P<b>\d+)$')"#,
+        );
+    }
+
+    #[pg_test]
+    fn test_copy_to_preset() {
+        use std::fs;
+
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+
+        let output_path = "/tmp/pgrx_test_copy_to_preset.md";
+        let _ = fs::remove_file(output_path);
+
+        // The header is rendered once, cells are escaped for the table
+        let query = format!(
+            "COPY (SELECT * FROM (VALUES (1, 'a|b'), (2, NULL)) AS t(id, label) ORDER BY id)
+             TO '{}' (FORMAT 'jinja', PRESET 'markdown')",
+            output_path
+        );
+        Spi::run(&query).expect("COPY with PRESET should succeed");
+
+        let contents = fs::read_to_string(output_path).expect("Should read output file");
+        assert_eq!(
+            contents,
+            "| id | label |\n| --- | --- |\n| 1 | a\\|b |\n| 2 |  |\n"
+        );
+
+        fs::remove_file(output_path).expect("Should clean up test file");
+    }
+
+    #[pg_test]
+    fn test_copy_to_preset_block_override() {
+        use std::fs;
+
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+
+        let output_path = "/tmp/pgrx_test_copy_to_preset_override.html";
+        let _ = fs::remove_file(output_path);
+
+        // Only the overridden block changes; the preset keeps the rest
+        let query = format!(
+            r#"COPY (SELECT 1 AS id, '<b>' AS label) TO '{}'
+               (FORMAT 'jinja', PRESET 'html_table', TEMPLATE '{{% extends "html_table" %}}{{% block footer %}}</tbody>
+<tfoot><tr><td colspan="2">end</td></tr></tfoot>
+</table>
+{{% endblock %}}')"#,
+            output_path
+        );
+        Spi::run(&query).expect("COPY with PRESET and TEMPLATE should succeed");
+
+        let contents = fs::read_to_string(output_path).expect("Should read output file");
+        assert_eq!(
+            contents,
+            "<table>\n<thead>\n<tr><th>id</th><th>label</th></tr>\n</thead>\n<tbody>\n\
+             <tr><td>1</td><td>&lt;b&gt;</td></tr>\n\
+             </tbody>\n<tfoot><tr><td colspan=\"2\">end</td></tr></tfoot>\n</table>\n"
+        );
+
+        fs::remove_file(output_path).expect("Should clean up test file");
+    }
+
+    #[pg_test]
+    fn test_copy_to_xml_preset() {
+        use std::fs;
+
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+
+        let output_path = "/tmp/pgrx_test_copy_to_xml_preset.xml";
+        let _ = fs::remove_file(output_path);
+
+        // Values are escaped for XML, not HTML: quotes and slashes stay as they are
+        let query = format!(
+            r#"COPY (SELECT 'a/b''s <c> & "d"' AS "the value", NULL::text AS xmlnote) TO '{}'
+               (FORMAT 'jinja', PRESET 'xml')"#,
+            output_path
+        );
+        Spi::run(&query).expect("COPY with PRESET should succeed");
+
+        let contents = fs::read_to_string(output_path).expect("Should read output file");
+        assert_eq!(
+            contents,
+            "<?xml version=\"1.0\"?>\n\
+             <rows xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\">\n\
+             <row>\n\
+             \x20 <the_x0020_value>a/b's &lt;c&gt; &amp; &quot;d&quot;</the_x0020_value>\n\
+             \x20 <_x0078_mlnote xsi:nil=\"true\"/>\n\
+             </row>\n\
+             </rows>\n"
+        );
+
+        fs::remove_file(output_path).expect("Should clean up test file");
+    }
+
+    #[pg_test(
+        error = "a TEMPLATE given with PRESET must extend it: start it with {% extends \"xml\" %}"
+    )]
+    fn test_copy_to_preset_template_without_extends() {
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+
+        let _ = Spi::run(
+            "COPY (SELECT 1 AS x) TO '/tmp/pgrx_test_copy_to_preset_without_extends.txt'
+             (FORMAT 'jinja', PRESET 'xml', TEMPLATE '{% block row %}{{ row.x }}{% endblock %}')",
+        );
+    }
+
+    #[pg_test(error = "invalid value for PRESET option: \"yaml\"")]
+    fn test_copy_to_unknown_preset() {
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+
+        let _ = Spi::run(
+            "COPY (SELECT 1 AS x) TO '/tmp/pgrx_test_copy_to_unknown_preset.txt'
+             (FORMAT 'jinja', PRESET 'yaml')",
+        );
+    }
+
+    #[pg_test]
+    fn test_copy_to_columns_metadata() {
+        use std::fs;
+
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+
+        let output_path = "/tmp/pgrx_test_copy_to_columns.txt";
+        let _ = fs::remove_file(output_path);
+
+        // `columns` describes the result, whatever the row contains
+        let query = format!(
+            "COPY (SELECT 1 AS id, 'x'::varchar(10) AS label, 2.5::numeric AS price)
+             TO '{}' (FORMAT 'jinja', TEMPLATE '{{% for c in columns %}}{{{{ c.position }}}}:{{{{ c.name }}}}:{{{{ c.type_name }}}}:{{{{ c.type_oid }}}}:{{{{ c.typmod }}}}:{{{{ c.nullable }}}}
+{{% endfor %}}')",
+            output_path
+        );
+        Spi::run(&query).expect("COPY using columns should succeed");
+
+        let contents = fs::read_to_string(output_path).expect("Should read output file");
+        assert_eq!(
+            contents,
+            "1:id:integer:23:-1:true\n\
+             2:label:character varying:1043:14:true\n\
+             3:price:numeric:1700:-1:true\n"
+        );
+
+        fs::remove_file(output_path).expect("Should clean up test file");
+    }
+
+    #[pg_test]
+    fn test_copy_to_columns_nullable_from_table() {
+        use std::fs;
+
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+        Spi::run(
+            "CREATE TEMP TABLE test_nullable (id INTEGER NOT NULL, note TEXT, code TEXT NOT NULL)",
+        )
+        .expect("Failed to create table");
+        Spi::run("INSERT INTO test_nullable VALUES (1, NULL, 'a')").expect("Failed to insert");
+
+        let output_path = "/tmp/pgrx_test_copy_to_columns_nullable.txt";
+        let _ = fs::remove_file(output_path);
+
+        // COPY table TO knows the table's NOT NULL columns, in any column order
+        let query = format!(
+            "COPY test_nullable (code, note, id)
+             TO '{}' (FORMAT 'jinja', TEMPLATE '{{% for c in columns %}}{{{{ c.name }}}}:{{{{ c.nullable }}}} {{% endfor %}}')",
+            output_path
+        );
+        Spi::run(&query).expect("COPY using columns should succeed");
+
+        let contents = fs::read_to_string(output_path).expect("Should read output file");
+        assert_eq!(contents, "code:false note:true id:false ");
+
+        fs::remove_file(output_path).expect("Should clean up test file");
+    }
+
+    #[pg_test]
+    fn test_copy_to_row_context() {
+        use std::fs;
+
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+
+        let output_path = "/tmp/pgrx_test_copy_to_row_context.txt";
+        let _ = fs::remove_file(output_path);
+
+        // Numbering and first-row handling without row_number() in SQL, plus
+        // the export-wide globals
+        let query = format!(
+            "COPY (SELECT * FROM (VALUES ('a'), ('b'), ('c')) AS t(letter) ORDER BY letter)
+             TO '{}' (FORMAT 'jinja', MAX_ROW_OUTPUT_BYTES '1MB', TEMPLATE '{{% if is_first %}}{{{{ options.format }}}} {{{{ options.max_row_output_bytes }}}} {{{{ database }}}} {{{{ current_user }}}} {{{{ query_started_at is string }}}}{{% endif %}}
+{{{{ rownum }}}}. {{{{ row.letter }}}}')",
+            output_path
+        );
+        Spi::run(&query).expect("COPY using row context should succeed");
+
+        let database = Spi::get_one::<String>("SELECT current_database()::text")
+            .expect("Should get database")
+            .unwrap();
+        let user = Spi::get_one::<String>("SELECT current_user::text")
+            .expect("Should get user")
+            .unwrap();
+
+        let contents = fs::read_to_string(output_path).expect("Should read output file");
+        assert_eq!(
+            contents,
+            format!("jinja 1MB {} {} true\n1. a\n2. b\n3. c", database, user)
+        );
+
+        fs::remove_file(output_path).expect("Should clean up test file");
+    }
+
+    #[pg_test]
+    fn test_copy_to_query_started_at_is_iso() {
+        use std::fs;
+
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+        Spi::run("SET DateStyle = 'SQL, DMY'").expect("Failed to set DateStyle");
+
+        let output_path = "/tmp/pgrx_test_copy_to_query_started_at.txt";
+        let _ = fs::remove_file(output_path);
+
+        let query = format!(
+            "COPY (SELECT 1 AS x) TO '{}' (FORMAT 'jinja', TEMPLATE '{{{{ query_started_at }}}}')",
+            output_path
+        );
+        Spi::run(&query).expect("COPY should succeed");
+
+        // The test runs as one statement, so its start time is the COPY's too
+        let expected = Spi::get_one::<String>("SELECT to_json(statement_timestamp()) #>> '{}'")
+            .expect("Should get statement timestamp")
+            .unwrap();
+        let contents = fs::read_to_string(output_path).expect("Should read output file");
+        assert_eq!(contents, expected);
+        assert_eq!(
+            &contents[10..11],
+            "T",
+            "Should be ISO 8601, got: {}",
+            contents
+        );
+
+        fs::remove_file(output_path).expect("Should clean up test file");
+    }
+
+    #[pg_test]
+    fn test_copy_to_positional_and_duplicate_columns() {
+        use std::fs;
+
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+
+        let output_path = "/tmp/pgrx_test_copy_to_duplicate_columns.txt";
+        let _ = fs::remove_file(output_path);
+
+        // Both ids stay reachable, by suffixed name and by position
+        let query = format!(
+            "COPY (SELECT 1 AS id, 2 AS id, 3)
+             TO '{}' (FORMAT 'jinja', TEMPLATE '{{{{ row.id }}}} {{{{ row.id_2 }}}} {{{{ row[2] }}}} {{{{ row|length }}}}
+{{% for name, value in row|items %}}{{{{ name }}}}={{{{ value }}}};{{% endfor %}}')",
+            output_path
+        );
+        Spi::run(&query).expect("COPY with duplicate column names should succeed");
+
+        let contents = fs::read_to_string(output_path).expect("Should read output file");
+        assert_eq!(contents, "1 2 3 3\nid=1;id_2=2;?column?=3;");
+
+        fs::remove_file(output_path).expect("Should clean up test file");
+    }
+
+    #[pg_test]
+    fn test_copy_to_params() {
+        use std::fs;
+
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+
+        let output_path = "/tmp/pgrx_test_copy_to_params.md";
+        let _ = fs::remove_file(output_path);
+
+        // Params reach the preset's header as well as every row
+        let query = format!(
+            r#"COPY (SELECT 'Alice' AS name) TO '{}'
+               (FORMAT 'jinja', PRESET 'markdown', PARAMS '{{"title": "Q3", "base_url": "https://example.com"}}',
+                TEMPLATE '{{% extends "markdown" %}}{{% block header %}}# {{{{ params.title }}}}
+{{% endblock %}}{{% block row %}}- [{{{{ row.name }}}}]({{{{ params.base_url }}}}/{{{{ row.name|lower }}}})
+{{% endblock %}}')"#,
+            output_path
+        );
+        Spi::run(&query).expect("COPY with PARAMS should succeed");
+
+        let contents = fs::read_to_string(output_path).expect("Should read output file");
+        assert_eq!(contents, "# Q3\n- [Alice](https://example.com/alice)\n");
+
+        fs::remove_file(output_path).expect("Should clean up test file");
+    }
+
+    #[pg_test(error = "PARAMS option must be a JSON object")]
+    fn test_copy_to_params_must_be_object() {
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+
+        let _ = Spi::run(
+            "COPY (SELECT 1 AS x) TO '/tmp/pgrx_test_copy_to_params_array.txt'
+             (FORMAT 'jinja', PARAMS '[1, 2]', TEMPLATE '{{ params }}')",
+        );
+    }
+
+    #[pg_test]
+    fn test_copy_to_session_globals() {
+        use std::fs;
+
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+        Spi::run(r#"SELECT pigiaminja.set_global('brand', '{"name": "Acme", "color": "red"}')"#)
+            .expect("set_global should succeed");
+
+        let output_path = "/tmp/pgrx_test_copy_to_session_globals.txt";
+        let _ = fs::remove_file(output_path);
+
+        let query = format!(
+            "COPY (SELECT 1 AS id) TO '{}'
+             (FORMAT 'jinja', TEMPLATE '{{{{ brand.name }}}} {{{{ brand.color }}}} {{{{ row.id }}}}')",
+            output_path
+        );
+        Spi::run(&query).expect("COPY using a session global should succeed");
+
+        let contents = fs::read_to_string(output_path).expect("Should read output file");
+        assert_eq!(contents, "Acme red 1");
+
+        // Once cleared, the global is undefined again
+        Spi::run("SELECT pigiaminja.clear_globals()").expect("clear_globals should succeed");
+        let query = format!(
+            "COPY (SELECT 1 AS id) TO '{}'
+             (FORMAT 'jinja', TEMPLATE '{{{{ brand is defined }}}}')",
+            output_path
+        );
+        Spi::run(&query).expect("COPY after clear_globals should succeed");
+
+        let contents = fs::read_to_string(output_path).expect("Should read output file");
+        assert_eq!(contents, "false");
+
+        fs::remove_file(output_path).expect("Should clean up test file");
+    }
+
+    #[pg_test]
+    fn test_copy_to_sql_filters() {
+        use std::fs;
+
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+        Spi::run(
+            "CREATE FUNCTION test_mask_email(email text) RETURNS text
+             LANGUAGE sql IMMUTABLE STRICT
+             RETURN left(email, 1) || '***@' || split_part(email, '@', 2)",
+        )
+        .expect("Failed to create filter function");
+        Spi::run(
+            "CREATE FUNCTION test_is_big(amount numeric, threshold int) RETURNS boolean
+             LANGUAGE sql IMMUTABLE RETURN amount > threshold",
+        )
+        .expect("Failed to create test function");
+        Spi::run("SELECT pigiaminja.register_filter('mask_email', 'test_mask_email(text)'::regprocedure)")
+            .expect("register_filter should succeed");
+        Spi::run(
+            "SELECT pigiaminja.register_test('big', 'test_is_big(numeric, int)'::regprocedure)",
+        )
+        .expect("register_test should succeed");
+
+        let output_path = "/tmp/pgrx_test_copy_to_sql_filters.txt";
+        let _ = fs::remove_file(output_path);
+
+        // NULL reaches a strict function as NULL and comes back as none
+        let query = format!(
+            "COPY (SELECT * FROM (VALUES ('alice@example.com', 150), (NULL, 20)) AS t(email, amount))
+             TO '{}' (FORMAT 'jinja', TEMPLATE '
+{{{{ row.email|mask_email }}}} {{{{ row.amount is big(100) }}}}')",
+            output_path
+        );
+        Spi::run(&query).expect("COPY with SQL filters should succeed");
+
+        let contents = fs::read_to_string(output_path).expect("Should read output file");
+        assert_eq!(contents, "\na***@example.com true\nnone false");
+
+        fs::remove_file(output_path).expect("Should clean up test file");
+    }
+
+    #[pg_test(error = "a template filter or test function must take at least one argument")]
+    fn test_register_filter_requires_argument() {
+        let _ = Spi::run("SELECT pigiaminja.register_filter('now', 'now()'::regprocedure)");
+    }
+
+    #[pg_test]
+    fn test_copy_to_query_function() {
+        use std::fs;
+
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+
+        Spi::run("SET pigiaminja.enable_query_function = true").expect("Failed to set GUC");
+        // Counts its calls in a session setting, which a read-only query may change
+        Spi::run(
+            "CREATE FUNCTION test_query_calls() RETURNS int STABLE LANGUAGE sql AS $$
+               SELECT set_config('pigiaminja_test.query_calls',
+                 (coalesce(nullif(current_setting('pigiaminja_test.query_calls', true), ''), '0')::int + 1)::text,
+                 false)::int
+             $$",
+        )
+        .expect("Failed to create function");
+
+        let output_path = "/tmp/pgrx_test_copy_to_query.txt";
+        let _ = fs::remove_file(output_path);
+
+        // The repeated key is served from the cache, so the function only
+        // runs once per distinct key
+        let query = format!(
+            r#"COPY (SELECT * FROM (VALUES (1, 'a'), (2, 'b'), (3, 'a')) AS t(id, k) ORDER BY id)
+               TO '{}' (FORMAT 'jinja', TEMPLATE '
+{{% for r in query("SELECT test_query_calls() AS n, upper($1) AS k", row.k) %}}{{{{ r.k }}}}{{{{ r.n }}}}{{% endfor %}}')"#,
+            output_path
+        );
+        Spi::run(&query).expect("COPY using query() should succeed");
+
+        let contents = fs::read_to_string(output_path).expect("Should read output file");
+        assert_eq!(contents, "\nA1\nB2\nA1");
+
+        fs::remove_file(output_path).expect("Should clean up test file");
+    }
+
+    #[pg_test(
+        error = "Failed to render Jinja template: invalid operation: query() is disabled, set pigiaminja.enable_query_function to use it (in row:1)"
+    )]
+    fn test_copy_to_query_function_disabled_by_default() {
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+
+        let _ = Spi::run(
+            r#"COPY (SELECT 1 AS x) TO '/tmp/pgrx_test_copy_to_query_disabled.txt'
+               (FORMAT 'jinja', TEMPLATE '{{ query("SELECT 1") }}')"#,
+        );
+    }
+
+    #[pg_test(error = "INSERT is not allowed in a non-volatile function")]
+    fn test_copy_to_query_function_is_read_only() {
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+        Spi::run("SET pigiaminja.enable_query_function = true").expect("Failed to set GUC");
+        Spi::run("CREATE TABLE test_query_writes (id int)").expect("Failed to create table");
+
+        let _ = Spi::run(
+            r#"COPY (SELECT 1 AS x) TO '/tmp/pgrx_test_copy_to_query_write.txt'
+               (FORMAT 'jinja', TEMPLATE '{{ query("INSERT INTO test_query_writes VALUES (1) RETURNING id") }}')"#,
+        );
+    }
+
+    #[pg_test(error = "cannot execute nextval() in a read-only transaction")]
+    fn test_copy_to_query_function_rejects_volatile_writes() {
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+        Spi::run("SET pigiaminja.enable_query_function = true").expect("Failed to set GUC");
+        Spi::run("CREATE SEQUENCE test_query_writes_seq").expect("Failed to create sequence");
+
+        let _ = Spi::run(
+            r#"COPY (SELECT 1 AS x) TO '/tmp/pgrx_test_copy_to_query_nextval.txt'
+               (FORMAT 'jinja', TEMPLATE '{{ query("SELECT nextval($$test_query_writes_seq$$)") }}')"#,
+        );
+    }
+
+    #[pg_test(
+        error = "Failed to render Jinja template: invalid operation: query() failed: SPI_ERROR_COPY (in row:1)"
+    )]
+    fn test_copy_to_query_function_spi_error() {
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+        Spi::run("SET pigiaminja.enable_query_function = true").expect("Failed to set GUC");
+
+        let _ = Spi::run(
+            r#"COPY (SELECT 1 AS x) TO '/tmp/pgrx_test_copy_to_query_spi_error.txt'
+               (FORMAT 'jinja', TEMPLATE '{{ query("COPY (SELECT 1) TO STDOUT") }}')"#,
+        );
+    }
+
+    #[pg_test]
+    fn test_copy_to_query_function_untyped_arguments() {
+        use std::fs;
+
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+        Spi::run("SET pigiaminja.enable_query_function = true").expect("Failed to set GUC");
+
+        let output_path = "/tmp/pgrx_test_copy_to_query_untyped.txt";
+        let _ = fs::remove_file(output_path);
+
+        // A string argument compared with an integer column takes its type
+        let query = format!(
+            r#"COPY (SELECT '2' AS id) TO '{}' (FORMAT 'jinja', TEMPLATE '{{{{ query("SELECT v FROM (VALUES (1, $$one$$), (2, $$two$$)) AS t(id, v) WHERE id = $1", row.id)[0].v }}}}')"#,
+            output_path
+        );
+        Spi::run(&query).expect("COPY using query() should succeed");
+
+        let contents = fs::read_to_string(output_path).expect("Should read output file");
+        assert_eq!(contents, "two");
+
+        fs::remove_file(output_path).expect("Should clean up test file");
+    }
+
+    #[pg_test]
+    fn test_copy_to_number_filters() {
+        use std::fs;
+
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+
+        let output_path = "/tmp/pgrx_test_copy_to_number_filters.txt";
+        let _ = fs::remove_file(output_path);
+
+        // numeric keeps its digits instead of going through a float; the C
+        // locale has no grouping and no currency symbol of its own
+        let query = format!(
+            r#"COPY (SELECT * FROM (VALUES (1, 12345678901234567.125::numeric, 0.256::float8), (2, -0.004, 3), (3, NULL, NULL)) AS t(id, amount, ratio) ORDER BY id)
+               TO '{}' (FORMAT 'jinja', TEMPLATE '
+{{{{ row.amount|number_format(2, "C") }}}} {{{{ row.amount|currency("EUR", "C") }}}} {{{{ row.ratio|percent(1, "C") }}}}')"#,
+            output_path
+        );
+        Spi::run(&query).expect("COPY with number filters should succeed");
+
+        let contents = fs::read_to_string(output_path).expect("Should read output file");
+        assert_eq!(
+            contents,
+            "\n12345678901234567.13 12345678901234567.13EUR 25.6%\n\
+             0.00 0.00EUR 300.0%\n\
+             none none none"
+        );
+
+        fs::remove_file(output_path).expect("Should clean up test file");
+    }
+
+    #[pg_test(
+        error = "Failed to render Jinja template: invalid operation: unknown locale \"xx_XX\" (in row:1)"
+    )]
+    fn test_copy_to_number_filters_unknown_locale() {
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+
+        let _ = Spi::run(
+            r#"COPY (SELECT 1 AS x) TO '/tmp/pgrx_test_copy_to_unknown_locale.txt'
+               (FORMAT 'jinja', TEMPLATE '{{ row.x|number_format(0, "xx_XX") }}')"#,
+        );
+    }
+
+    #[pg_test]
+    fn test_copy_to_translations() {
+        use std::fs;
+
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+        Spi::run(
+            r#"INSERT INTO pigiaminja.translations (locale, msgid, msgstr, plural_forms) VALUES
+                ('pl', '', 'Plural-Forms: nplurals=3; plural=(n==1 ? 0 : n%10>=2 && n%10<=4 && (n%100<10 || n%100>=20) ? 1 : 2);', NULL),
+                ('pl', 'Order', 'Zamówienie', NULL),
+                ('pl', 'Total', 'Razem', NULL),
+                ('pl_PL', 'Total', 'Suma', NULL),
+                ('pl', '%d item', '%d pozycja', ARRAY['%d pozycje', '%d pozycji'])"#,
+        )
+        .expect("Failed to insert translations");
+
+        let output_path = "/tmp/pgrx_test_copy_to_translations.txt";
+        let _ = fs::remove_file(output_path);
+
+        // pl_PL.UTF-8 falls back to pl_PL, then pl; untranslated text renders
+        // as written
+        let query = format!(
+            r#"COPY (SELECT * FROM (VALUES (1), (3), (5), (22)) AS t(n) ORDER BY n)
+               TO '{}' (FORMAT 'jinja', LOCALE 'pl_PL.UTF-8', TEMPLATE '
+{{{{ _("Order") }}}} {{{{ _("Total") }}}} {{{{ _("Status") }}}}: {{{{ ngettext("%d item", "%d items", row.n)|format(row.n) }}}}')"#,
+            output_path
+        );
+        Spi::run(&query).expect("COPY with translations should succeed");
+
+        let contents = fs::read_to_string(output_path).expect("Should read output file");
+        assert_eq!(
+            contents,
+            "\nZamówienie Suma Status: 1 pozycja\n\
+             Zamówienie Suma Status: 3 pozycje\n\
+             Zamówienie Suma Status: 5 pozycji\n\
+             Zamówienie Suma Status: 22 pozycje"
+        );
+
+        fs::remove_file(output_path).expect("Should clean up test file");
+    }
+
+    #[pg_test]
+    fn test_copy_to_render_threads() {
+        use std::fs;
+
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+        Spi::run(
+            "CREATE FUNCTION test_shout(value text) RETURNS text
+             LANGUAGE sql IMMUTABLE STRICT RETURN upper(value)",
+        )
+        .expect("Failed to create filter function");
+        Spi::run("SELECT pigiaminja.register_filter('shout', 'test_shout(text)'::regprocedure)")
+            .expect("register_filter should succeed");
+
+        let output_path = "/tmp/pgrx_test_copy_to_render_threads.txt";
+        let export = |template: &str| {
+            let _ = fs::remove_file(output_path);
+            Spi::run(&format!(
+                "COPY (SELECT i, 'row ' || i AS label FROM generate_series(1, 1000) AS i)
+                 TO '{}' (FORMAT 'jinja', TEMPLATE '{}')",
+                output_path, template
+            ))
+            .expect("COPY should succeed");
+            fs::read_to_string(output_path).expect("Should read output file")
+        };
+
+        // Rows come out in order whatever thread rendered them, and a filter
+        // that calls into Postgres still works, on the backend
+        let templates = [
+            "\n{{ rownum }} {{ row.label }} {{ is_first }}",
+            "\n{{ rownum }} {{ row.label|shout }}",
+        ];
+        for template in templates {
+            Spi::run("SET pigiaminja.render_threads = 1").expect("Failed to set GUC");
+            let serial = export(template);
+            Spi::run("SET pigiaminja.render_threads = 4").expect("Failed to set GUC");
+            let parallel = export(template);
+
+            assert_eq!(serial.matches('\n').count(), 1000);
+            assert_eq!(parallel, serial);
+        }
+
+        fs::remove_file(output_path).expect("Should clean up test file");
+    }
+
+    #[pg_test(error = "jinja COPY output exceeds max_row_output_bytes (1024 bytes)")]
+    fn test_render_threads_row_output_limit() {
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+        Spi::run("SET pigiaminja.render_threads = 4").expect("Failed to set GUC");
+        Spi::run("SET pigiaminja.max_row_output_bytes = '1kB'").expect("Failed to set GUC");
+
+        let _ = Spi::run(
+            "COPY (SELECT i FROM generate_series(1, 200) AS i)
+             TO '/tmp/pgrx_test_render_threads_row_output_limit.txt'
+             (FORMAT 'jinja', TEMPLATE '{% if row.i == 150 %}{% for i in range(2000) %}x{% endfor %}{% endif %}')",
+        );
+    }
+
+    #[pg_test]
+    fn test_render_threads_binary_output() {
+        use std::fs;
+
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+        Spi::run("SET pigiaminja.render_threads = 4").expect("Failed to set GUC");
+        Spi::run("SET pigiaminja.max_row_output_bytes = '1kB'").expect("Failed to set GUC");
+
+        let output_path = "/tmp/pgrx_test_render_threads_binary_output.bin";
+        let _ = fs::remove_file(output_path);
+
+        // 600 bytes a row: within the cap once written, though not as text
+        let query = format!(
+            r#"COPY (SELECT i, decode(repeat('00ff', 300), 'hex') AS b FROM generate_series(1, 200) AS i)
+               TO '{}' (FORMAT 'jinja', BINARY_OUTPUT true, TEMPLATE '{{{{ row.b }}}}')"#,
+            output_path
+        );
+        Spi::run(&query).expect("COPY with BINARY_OUTPUT on render threads should succeed");
+
+        let contents = fs::read(output_path).expect("Should read output file");
+        assert_eq!(contents, [0x00, 0xff].repeat(300 * 200));
+
+        fs::remove_file(output_path).expect("Should clean up test file");
+    }
+
+    #[pg_test]
+    fn test_copy_to_unreferenced_columns() {
+        use std::fs;
+
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+
+        let output_path = "/tmp/pgrx_test_copy_to_unreferenced_columns.txt";
+        let query = "SELECT 1 AS a, repeat('x', 100000) AS big, 2 AS id, 3 AS id";
+        let export = |template: &str| {
+            let _ = fs::remove_file(output_path);
+            Spi::run(&format!(
+                "COPY ({}) TO '{}' (FORMAT 'jinja', TEMPLATE '{}')",
+                query, output_path, template
+            ))
+            .expect("COPY should succeed");
+            fs::read_to_string(output_path).expect("Should read output file")
+        };
+
+        // Only the columns named in row.<column> are converted, including
+        // inside macros; `big` is never read
+        assert_eq!(
+            export("{% macro m() %}{{ row.id }}{% endmacro %}{{ row.a }} {{ row.id_2 }} {{ m() }}"),
+            "1 3 2"
+        );
+        // Using row as a whole may read any column, so all are converted
+        assert_eq!(
+            export("{% set r = row %}{{ r.big|length }} {{ row[0] }}"),
+            "100000 1"
+        );
+
+        fs::remove_file(output_path).expect("Should clean up test file");
+    }
+
+    #[pg_test]
+    fn test_copy_to_lazy_cells() {
+        use std::fs;
+
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+
+        let output_path = "/tmp/pgrx_test_copy_to_lazy_cells.txt";
+        let _ = fs::remove_file(output_path);
+
+        // Cells are converted when first read, and read the same after that
+        let query = format!(
+            r#"COPY (SELECT * FROM (VALUES (1, true, '{{"a": 1}}'::jsonb), (2, false, NULL), (3, NULL, '{{"a": 3}}')) AS t(id, flag, doc) ORDER BY id)
+               TO '{}' (FORMAT 'jinja', TEMPLATE '{{% if row.flag %}}{{{{ row.doc.a }}}}{{{{ row.doc.a }}}}{{% else %}}{{{{ row.id }}}} {{{{ row.doc is none }}}}{{% endif %}} {{{{ row|length }}}};')"#,
+            output_path
+        );
+        Spi::run(&query).expect("COPY with conditional reads should succeed");
+
+        let contents = fs::read_to_string(output_path).expect("Should read output file");
+        assert_eq!(contents, "11 3;2 true 3;3 false 3;");
+
+        fs::remove_file(output_path).expect("Should clean up test file");
+    }
+
+    #[pg_test]
+    fn test_copy_to_memory_stays_flat() {
+        use std::fs;
+
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+        Spi::run("SET pigiaminja.enable_query_function = on").expect("Failed to set GUC");
+
+        let output_path = "/tmp/pgrx_test_copy_to_memory_stays_flat.txt";
+        let _ = fs::remove_file(output_path);
+
+        // numeric and timestamptz go through their output functions, which
+        // allocate on every row. Measure the backend's memory early in the
+        // export and near its end.
+        let query = format!(
+            r#"COPY (SELECT i, i::numeric AS a, (i * 2)::numeric AS b, now() + i * interval '1 second' AS t FROM generate_series(1, 100000) AS i)
+               TO '{}' (FORMAT 'jinja', TEMPLATE '{{{{ row.a }}}}{{{{ row.b }}}}{{{{ row.t }}}}{{% if row.i in [1000, 100000] %}}
+{{{{ query("SELECT sum(total_bytes) AS bytes FROM pg_backend_memory_contexts WHERE $1 > 0", row.i)[0].bytes }}}}
+{{% endif %}}')"#,
+            output_path
+        );
+        Spi::run(&query).expect("COPY should succeed");
+
+        let contents = fs::read_to_string(output_path).expect("Should read output file");
+        let measured: Vec<i64> = contents
+            .lines()
+            .filter_map(|line| line.parse().ok())
+            .collect();
+        assert_eq!(
+            measured.len(),
+            2,
+            "expected two measurements in {:?}",
+            measured
+        );
+
+        // Leaking the three converted values of 99,000 rows would be megabytes
+        let growth = measured[1] - measured[0];
+        assert!(
+            growth < 1024 * 1024,
+            "backend memory grew by {} bytes during the export",
+            growth
+        );
+
+        fs::remove_file(output_path).expect("Should clean up test file");
+    }
+
+    #[pg_test]
+    fn test_copy_to_binary_output() {
+        use std::fs;
+
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+
+        let output_path = "/tmp/pgrx_test_copy_to_binary_output.bin";
+        let _ = fs::remove_file(output_path);
+
+        let query = format!(
+            r#"COPY (SELECT '\x00ff0a'::bytea AS b, 'é' AS t, chr(1114880) AS c)
+               TO '{}' (FORMAT 'jinja', BINARY_OUTPUT true, TEMPLATE '{{% macro m(v) %}}<{{{{ v }}}}>{{% endmacro %}}[{{{{ row.b }}}}|{{{{ row.t }}}}|{{{{ row.c }}}}|{{{{ m(row.b) }}}}]')"#,
+            output_path
+        );
+        Spi::run(&query).expect("COPY with BINARY_OUTPUT should succeed");
+
+        // Text is written as UTF-8 whatever it contains, bytea as its bytes,
+        // also when printed by a macro
+        let contents = fs::read(output_path).expect("Should read output file");
+        assert_eq!(
+            contents,
+            b"[\x00\xff\n|\xc3\xa9|\xf4\x8f\xbc\x80|<\x00\xff\n>]"
+        );
+
+        // Without it, bytea renders as its hex text as before
+        let query = format!(
+            r#"COPY (SELECT '\x00ff0a'::bytea AS b)
+               TO '{}' (FORMAT 'jinja', TEMPLATE '[{{{{ row.b }}}}]')"#,
+            output_path
+        );
+        Spi::run(&query).expect("COPY should succeed");
+
+        let contents = fs::read_to_string(output_path).expect("Should read output file");
+        assert_eq!(contents, "[\\x00ff0a]");
+
+        fs::remove_file(output_path).expect("Should clean up test file");
+    }
+
+    #[pg_test]
+    fn test_copy_to_limit_rows() {
+        use std::fs;
+
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+
+        let output_path = "/tmp/pgrx_test_copy_to_limit_rows.txt";
+        let _ = fs::remove_file(output_path);
+
+        let query = format!(
+            "COPY (SELECT i FROM generate_series(1, 100) AS i) TO '{}'
+             (FORMAT 'jinja', LIMIT_ROWS 3, TEMPLATE '{{{{ row.i }}}};')",
+            output_path
+        );
+        Spi::run(&query).expect("COPY with LIMIT_ROWS should succeed");
+
+        let contents = fs::read_to_string(output_path).expect("Should read output file");
+        assert_eq!(contents, "1;2;3;");
+
+        fs::remove_file(output_path).expect("Should clean up test file");
+    }
+
+    #[pg_test]
+    fn test_copy_to_dry_run() {
+        use std::fs;
+
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+
+        let output_path = "/tmp/pgrx_test_copy_to_dry_run.txt";
+        let _ = fs::remove_file(output_path);
+
+        let query = format!(
+            "COPY (SELECT i FROM generate_series(1, 100) AS i) TO '{}'
+             (FORMAT 'jinja', DRY_RUN true, TEMPLATE '{{{{ row.i }}}};')",
+            output_path
+        );
+        Spi::run(&query).expect("COPY with DRY_RUN should succeed");
+
+        assert!(
+            fs::metadata(output_path).is_err(),
+            "DRY_RUN should not create the output file"
+        );
+    }
+
+    #[pg_test(error = "LIMIT_ROWS must not be negative: -1")]
+    fn test_copy_to_negative_limit_rows() {
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+
+        let _ = Spi::run(
+            "COPY (SELECT 1 AS x) TO '/tmp/pgrx_test_copy_to_negative_limit_rows.txt'
+             (FORMAT 'jinja', LIMIT_ROWS -1, TEMPLATE '{{ row.x }}')",
+        );
+    }
+
+    #[pg_test]
+    fn test_copy_to_progress() {
+        use std::fs;
+
+        Spi::run("SET pigiaminja.enable_copy_hooks = true").expect("Failed to set GUC");
+        Spi::run("SET pigiaminja.enable_query_function = on").expect("Failed to set GUC");
+
+        let output_path = "/tmp/pgrx_test_copy_to_progress.txt";
+        let _ = fs::remove_file(output_path);
+
+        // The third row looks itself up while the export runs: the two
+        // before it have been counted
+        let query = format!(
+            r#"COPY (SELECT i FROM generate_series(1, 5) AS i) TO '{}'
+               (FORMAT 'jinja', TEMPLATE '{{% if row.i == 3 %}}{{% for p in query("SELECT command, type, tuples_processed, bytes_processed FROM pg_stat_progress_copy WHERE pid = pg_backend_pid()") %}}{{{{ p.command }}}} {{{{ p.type }}}} {{{{ p.tuples_processed }}}} {{{{ p.bytes_processed }}}}{{% endfor %}};{{% else %}}{{{{ row.i }}}};{{% endif %}}')"#,
+            output_path
+        );
+        Spi::run(&query).expect("COPY should succeed");
+
+        let contents = fs::read_to_string(output_path).expect("Should read output file");
+        assert_eq!(contents, "1;2;COPY TO FILE 2 4;4;5;");
+
+        // And the entry is gone once it is done
+        Spi::run("SELECT pg_stat_clear_snapshot()").expect("Failed to clear snapshot");
+        let remaining = Spi::get_one::<i64>(
+            "SELECT count(*) FROM pg_stat_progress_copy WHERE pid = pg_backend_pid()",
+        )
+        .expect("Failed to query progress view");
+        assert_eq!(remaining, Some(0));
+
+        fs::remove_file(output_path).expect("Should clean up test file");
+    }
 }
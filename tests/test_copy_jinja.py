@@ -33,6 +33,7 @@ Usage:
 """
 
 import argparse
+import re
 import sys
 
 import psycopg
@@ -112,6 +113,22 @@ class Harness:
             return
         self.check(name, got, expected)
 
+    def error(self, name, query, template, fragment, setup=()):
+        """Assert rendering fails with an error whose message contains
+        `fragment`. `setup` statements run first on the same session and are
+        RESET afterwards."""
+        try:
+            for stmt in setup:
+                self.conn.execute(stmt)
+            self.render(query, template)
+        except psycopg.Error as e:
+            self.check(name, fragment in str(e), True)
+        else:
+            self.failed += 1
+            print(f"  \033[31m✗ {name}  (did not raise)\033[0m")
+        finally:
+            self.conn.execute("RESET ALL")
+
     def differential(self, name, query, columns, fsep=",", rowpfx="\n"):
         """
         Build a row template that PREFIXES each row with `rowpfx` (a newline by
@@ -404,6 +421,11 @@ def test_scale_ordering(h):
     )
     cols = ["id", "label", "m"]
     h.differential("1000 rows, order preserved", query, cols, fsep=":")
+    # STDOUT output goes out in CopyData messages of copy_message_size; a
+    # small one splits the export over many messages, and rows across them
+    h.conn.execute("SET pigiaminja.copy_message_size = '1kB'")
+    h.differential("1000 rows over 1kB messages", query, cols, fsep=":")
+    h.conn.execute("RESET pigiaminja.copy_message_size")
 
 
 def test_jsonb_query_driven(h):
@@ -423,6 +445,131 @@ def test_jsonb_query_driven(h):
     h.golden("per-row json branching", query, tmpl, expected)
 
 
+def test_render_limits(h):
+    print("\nRender limits (fuel / recursion):")
+    q = "SELECT 1 AS x"
+    h.error("max_fuel stops a runaway loop", q,
+            "{% for i in range(100000) %}{% for j in range(100000) %}{% endfor %}{% endfor %}",
+            "fuel", setup=["SET pigiaminja.max_fuel = 10000"])
+    h.golden("max_fuel leaves ordinary rows alone", q,
+             "{% for i in range(3) %}{{ i }}{% endfor %}", "012")
+    h.error("max_recursion bounds nesting", q,
+            "{% macro f(n) %}{{ f(n + 1) }}{% endmacro %}{{ f(0) }}",
+            "recursion", setup=["SET pigiaminja.max_recursion = 20"])
+
+
+def test_program_reaped_on_error(h):
+    # Regression: an ERROR mid-export used to skip the receiver's shutdown, so a
+    # COPY TO PROGRAM child kept its stdin open and was never waited on. The
+    # abort cleanup now closes the pipe and reaps it, so by the time the failed
+    # statement returns the program has seen EOF and flushed what it got.
+    print("\nAbort cleanup for COPY TO PROGRAM (golden):")
+    path = "/tmp/pigiaminja_abort_cleanup.txt"
+    sql = (
+        "COPY (SELECT i AS x FROM generate_series(1, 5) AS s(i) ORDER BY i) "
+        f"TO PROGRAM 'cat > {path}' "
+        f"(FORMAT 'jinja', TEMPLATE ${TAG}$\n{{{{row.x}}}}:{{{{ 1 // (3 - row.x) }}}}${TAG}$)"
+    )
+    try:
+        h.conn.execute(sql)
+        h.failed += 1
+        print("  \033[31m✗ render error did not abort the COPY\033[0m")
+        return
+    except psycopg.Error:
+        pass
+    got = h.fetch(f"SELECT pg_read_file('{path}')")[0][0]
+    h.check("rows before the error reach the program", got, "\n1:0\n2:1")
+
+
+def test_copy_from_stdin(h):
+    # COPY FROM STDIN runs the copy-in sub-protocol itself, so check a real
+    # client round trip: chunk boundaries inside lines, CRLF line endings and
+    # characters that are special to COPY's text format.
+    print("\nCOPY FROM STDIN with FORMAT 'jinja' (golden):")
+    h.conn.execute(
+        "CREATE TEMP TABLE IF NOT EXISTS jinja_import (ts date, level text, msg text)"
+    )
+    h.conn.execute("TRUNCATE jinja_import")
+    data = (
+        b"2024-01-02 [INFO] started\r\n"
+        b"2024-01-02 [WARN] tab\there, back\\slash\n"
+        b"2024-01-03 [ERROR] \\N is not null\n"
+    )
+    sql = (
+        "COPY jinja_import FROM STDIN (FORMAT 'jinja', "
+        "TEMPLATE '{{ ts }} [{{ level }}] {{ msg }}') WHERE level <> 'INFO'"
+    )
+    with h.conn.cursor().copy(sql) as copy:
+        for i in range(0, len(data), 7):
+            copy.write(data[i:i + 7])
+    got = h.fetch("SELECT ts::text, level, msg FROM jinja_import ORDER BY ts, level")
+    h.check("rows parsed, filtered and unescaped", got, [
+        ("2024-01-02", "WARN", "tab\there, back\\slash"),
+        ("2024-01-03", "ERROR", "\\N is not null"),
+    ])
+
+
+def test_copy_message_size(h):
+    # Rows are batched into CopyData messages of about copy_message_size; each
+    # chunk psycopg yields is one message, so a small size shows up as many
+    # chunks that together still hold every row, in order.
+    print("\nCopyData batching (copy_message_size):")
+    sql = (
+        "COPY (SELECT i FROM generate_series(1, 500) AS s(i)) TO STDOUT "
+        f"(FORMAT 'jinja', TEMPLATE ${TAG}$\n{{{{ row.i }}}}${TAG}$)"
+    )
+    expected = "".join(f"\n{i}" for i in range(1, 501))
+    for size, expected_chunks in (("1kB", 2), ("0", 500)):
+        h.conn.execute(f"SET pigiaminja.copy_message_size = '{size}'")
+        chunks = []
+        try:
+            with h.conn.cursor().copy(sql) as copy:
+                for chunk in copy:
+                    chunks.append(bytes(chunk))
+        finally:
+            h.conn.execute("RESET pigiaminja.copy_message_size")
+        h.check(f"copy_message_size = {size}: rows", b"".join(chunks).decode(), expected)
+        h.check(f"copy_message_size = {size}: messages", len(chunks), expected_chunks)
+
+
+def test_dry_run_over_stdout(h):
+    # DRY_RUN TO STDOUT still has to answer with a COPY OUT (an empty one), or
+    # cursor.copy() fails; the timings come in a NOTICE.
+    print("\nDRY_RUN over STDOUT:")
+    notices = []
+    h.conn.add_notice_handler(notices.append)
+    sql = (
+        "COPY (SELECT i FROM generate_series(1, 3) AS s(i)) TO STDOUT "
+        f"(FORMAT 'jinja', DRY_RUN true, TEMPLATE ${TAG}${{{{ row.i }}}};${TAG}$)"
+    )
+    data = bytearray()
+    try:
+        with h.conn.cursor().copy(sql) as copy:
+            for chunk in copy:
+                data += chunk
+    except psycopg.Error as e:
+        h.failed += 1
+        print(f"  \033[31m✗ DRY_RUN over STDOUT  (raised: {e})\033[0m")
+        h.reconnect()
+        return
+    finally:
+        h.conn.remove_notice_handler(notices.append)
+    h.check("nothing is sent", bytes(data), b"")
+
+    timed = [n for n in notices if n.message_primary.startswith("jinja COPY")]
+    h.check("one timing NOTICE", len(timed), 1)
+    if timed:
+        ms = r"\d+\.\d{3} ms"
+        h.check("NOTICE message", bool(re.fullmatch(
+            rf"jinja COPY rendered 3 rows \(6 bytes\) in {ms}", timed[0].message_primary
+        )), True)
+        h.check("NOTICE detail", bool(re.fullmatch(
+            rf"query execution: {ms}, datum conversion: {ms}, rendering: {ms}, "
+            r"writing: skipped \(DRY_RUN\)",
+            timed[0].message_detail or "",
+        )), True)
+
+
 # --- Main --------------------------------------------------------------------
 
 def main():
@@ -457,6 +604,11 @@ def main():
     test_complex_subquery(h)
     test_scale_ordering(h)
     test_jsonb_query_driven(h)
+    test_render_limits(h)
+    test_program_reaped_on_error(h)
+    test_copy_from_stdin(h)
+    test_copy_message_size(h)
+    test_dry_run_over_stdout(h)
 
     print("\n" + "=" * 60)
     total = h.passed + h.failed